@@ -12,4 +12,47 @@ pub enum Error {
     PropertyAlreadyExists(&'static str),
     #[error("invalid property id - Malformed packet")]
     InvalidPropertyID(u32),
+    #[error("property length is more than the permissible 268435455 bytes")]
+    PropertyLengthTooLarge,
+    #[error("length of {0} bytes is more than the permissible 268435455 bytes")]
+    LengthTooLarge(usize),
+}
+
+impl Error {
+    // reason_code returns the MQTT reason code to report the error with, e.g.
+    // in a DISCONNECT. Most of the decoding errors are a Malformed Packet
+    // (0x81), a repeated property is a Protocol Error (0x82) and a property
+    // block too large to encode is a Packet too large (0x95).
+    pub fn reason_code(&self) -> u8 {
+        match self {
+            Error::PropertyAlreadyExists(_) => 0x82,
+            Error::InvalidUTF8String
+            | Error::MalformedPacket
+            | Error::InvalidVarUint32(_)
+            | Error::InvalidVarUint32Length(_)
+            | Error::InvalidPropertyID(_)
+            | Error::LengthTooLarge(_) => 0x81,
+            Error::PropertyLengthTooLarge => 0x95,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    #[test]
+    fn test_reason_code() {
+        assert_eq!(Error::InvalidUTF8String.reason_code(), 0x81);
+        assert_eq!(Error::MalformedPacket.reason_code(), 0x81);
+        assert_eq!(Error::InvalidVarUint32(5).reason_code(), 0x81);
+        assert_eq!(Error::InvalidVarUint32Length(128).reason_code(), 0x81);
+        assert_eq!(Error::InvalidPropertyID(0x7F).reason_code(), 0x81);
+        assert_eq!(Error::LengthTooLarge(268435456).reason_code(), 0x81);
+        assert_eq!(
+            Error::PropertyAlreadyExists("Receive Maximum").reason_code(),
+            0x82
+        );
+        assert_eq!(Error::PropertyLengthTooLarge.reason_code(), 0x95);
+    }
 }
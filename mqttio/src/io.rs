@@ -3,12 +3,13 @@ use std::io::{self, Read};
 use std::str::{self};
 
 use crate::errors::Error;
+use crate::properties::Properties;
 
 pub type KeyValuePair = (String, String);
 
 type BinaryType = Vec<u8>;
 
-const MAX_VARUINT32: u32 = 268435455;
+pub(crate) const MAX_VARUINT32: u32 = 268435455;
 pub struct VarUint32Size {}
 
 impl VarUint32Size {
@@ -54,6 +55,30 @@ fn validate_utf8_chars(v: &str) -> bool {
     true
 }
 
+// borrow_utf8_string reads a UTF-8 string from the start of buf without
+// copying it, returning the string and the number of bytes it took, the
+// length prefix included.
+pub fn borrow_utf8_string(buf: &[u8]) -> Result<(&str, usize), Error> {
+    let (len, rest) = match buf {
+        [hi, lo, rest @ ..] => (usize::from(u16::from_be_bytes([*hi, *lo])), rest),
+        _ => return Err(Error::MalformedPacket),
+    };
+    let data = rest.get(..len).ok_or(Error::MalformedPacket)?;
+    match std::str::from_utf8(data) {
+        Ok(v) if validate_utf8_chars(v) => Ok((v, 2 + len)),
+        _ => Err(Error::InvalidUTF8String),
+    }
+}
+
+// borrow_key_value_pair is the borrowed form of Reader::read_key_value_pair
+// for a packet held in memory, e.g. to inspect the user properties without
+// allocating. The pair points into buf.
+pub fn borrow_key_value_pair(buf: &[u8]) -> Result<((&str, &str), usize), Error> {
+    let (key, key_len) = borrow_utf8_string(buf)?;
+    let (value, value_len) = borrow_utf8_string(&buf[key_len..])?;
+    Ok(((key, value), key_len + value_len))
+}
+
 pub trait Reader: io::Read {
     fn read_bool(&mut self) -> Result<bool, Error> {
         let v = self.read_u8()?;
@@ -61,17 +86,17 @@ pub trait Reader: io::Read {
     }
 
     fn read_u8(&mut self) -> Result<u8, Error> {
-        let buf = Reader::read_exact::<1>(self)?;
+        let buf = Reader::read_array::<1>(self)?;
         return Ok(buf[0]);
     }
 
     fn read_u16(&mut self) -> Result<u16, Error> {
-        let buf = Reader::read_exact::<2>(self)?;
+        let buf = Reader::read_array::<2>(self)?;
         return Ok(u16::from_be_bytes(buf));
     }
 
     fn read_u32(&mut self) -> Result<u32, Error> {
-        let buf = Reader::read_exact::<4>(self)?;
+        let buf = Reader::read_array::<4>(self)?;
 
         return Ok(u32::from_be_bytes(buf));
     }
@@ -88,11 +113,13 @@ pub trait Reader: io::Read {
                 return Err(Error::InvalidVarUint32(consumed));
             }
 
-            value += (encoded_byte as u32 & 0x7f) * multiplier;
+            value = accumulate_varuint32(value, encoded_byte, multiplier)
+                .ok_or(Error::InvalidVarUint32(consumed))?;
             if (encoded_byte & 0x80) == 0 {
                 break;
             }
 
+            // at most 128^4, the guard stops before it could overflow
             multiplier *= 128;
             if multiplier > 128 * 128 * 128 {
                 return Err(Error::InvalidVarUint32Length(multiplier));
@@ -104,11 +131,7 @@ pub trait Reader: io::Read {
 
     fn read_binary(&mut self) -> Result<BinaryType, Error> {
         let size = self.read_u16()?;
-
-        let mut data: Vec<u8> = Vec::with_capacity(usize::from(size));
-        data.resize(usize::from(size), 0);
-        self.read_exact_buf(&mut data)?;
-        return Ok(data);
+        self.read_vec(usize::from(size))
     }
 
     fn read_utf8_string(&mut self) -> Result<String, Error> {
@@ -130,12 +153,52 @@ pub trait Reader: io::Read {
         return Ok((key, value));
     }
 
-    fn read_exact<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+    // read_until reads a list of items that runs until the remaining length of
+    // the packet is consumed, e.g. SUBSCRIBE topic filters or SUBACK reason
+    // codes. f reads one item and returns the number of bytes it consumed, which
+    // is taken off remaining. An item that consumes nothing or more than what is
+    // left is a malformed packet.
+    fn read_until<T, F>(&mut self, remaining: &mut u32, mut f: F) -> Result<Vec<T>, Error>
+    where
+        F: FnMut(&mut Self) -> Result<(u32, T), Error>,
+    {
+        let mut items = Vec::new();
+        while *remaining > 0 {
+            let (consumed, item) = f(self)?;
+            if consumed == 0 || consumed > *remaining {
+                return Err(Error::MalformedPacket);
+            }
+            *remaining -= consumed;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    /// Reads exactly N bytes into an array, e.g. the fixed size fields of a
+    /// header. A short read is a malformed packet. io::Read has an unstable
+    /// method of the same name, call it as `Reader::read_array::<N>(r)`.
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
         let mut buf: [u8; N] = [0; N];
         self.read_exact_buf(&mut buf)?;
         return Ok(buf);
     }
 
+    /// Reads exactly n bytes into a vector. n usually comes from the packet,
+    /// it is checked against the largest remaining length and the vector only
+    /// grows as the bytes arrive, so a declared length alone cannot force a
+    /// large allocation.
+    fn read_vec(&mut self, n: usize) -> Result<Vec<u8>, Error> {
+        if n > MAX_VARUINT32 as usize {
+            return Err(Error::LengthTooLarge(n));
+        }
+        let mut buf = Vec::new();
+        let result = Read::take(&mut *self, n as u64).read_to_end(&mut buf);
+        if result.is_err() || buf.len() != n {
+            return Err(Error::MalformedPacket);
+        }
+        Ok(buf)
+    }
+
     fn read_exact_buf(&mut self, buf: &mut [u8]) -> Result<(), Error> {
         let result = Read::read_exact(self, buf);
 
@@ -148,6 +211,16 @@ pub trait Reader: io::Read {
 
 impl<R: io::Read + ?Sized> Reader for R {}
 
+// accumulate_varuint32 adds the low seven bits of an encoded byte at the
+// position of multiplier to value. With the four byte and multiplier guards of
+// read_varuint32 the result stays within MAX_VARUINT32, None is only returned
+// for inputs those guards already reject.
+fn accumulate_varuint32(value: u32, encoded_byte: u8, multiplier: u32) -> Option<u32> {
+    (encoded_byte as u32 & 0x7f)
+        .checked_mul(multiplier)
+        .and_then(|v| value.checked_add(v))
+}
+
 pub trait Writer: io::Write {
     fn write_bool(&mut self, value: bool) -> Result<(), Error> {
         self.write_internal(&[value as u8])
@@ -207,6 +280,19 @@ pub trait Writer: io::Write {
         self.write_utf8_string(value)
     }
 
+    // write_property_block writes the length of a property block followed by
+    // the properties, an absent block is written as a zero length
+    fn write_property_block<P: Properties>(&mut self, props: Option<&P>) -> Result<(), P::Error>
+    where
+        Self: Sized,
+        P::Error: From<Error>,
+    {
+        match props {
+            Some(p) => p.write_block(self),
+            None => Ok(self.write_varuint32(0)?),
+        }
+    }
+
     fn write_internal(&mut self, buf: &[u8]) -> Result<(), Error> {
         let result = self.write_all(buf);
         if result.is_err() {
@@ -219,16 +305,101 @@ pub trait Writer: io::Write {
 
 impl<W: io::Write + ?Sized> Writer for W {}
 
+// TeeReader keeps a copy of every byte read through it, so that a caller can
+// decode a value and still get hold of its exact encoding.
+pub struct TeeReader<'a, R: ?Sized> {
+    inner: &'a mut R,
+    consumed: Vec<u8>,
+}
+
+impl<'a, R: io::Read + ?Sized> TeeReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            consumed: Vec::new(),
+        }
+    }
+
+    pub fn into_consumed(self) -> Vec<u8> {
+        self.consumed
+    }
+}
+
+impl<R: io::Read + ?Sized> io::Read for TeeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// CountingReader counts the bytes read through it, e.g. to find out how long a
+// decoded value was on the wire without keeping a copy of it.
+pub struct CountingReader<'a, R: ?Sized> {
+    inner: &'a mut R,
+    count: u64,
+}
+
+impl<'a, R: io::Read + ?Sized> CountingReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: io::Read + ?Sized> io::Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+// CountingWriter discards what is written to it and only counts the bytes,
+// e.g. to check the encoded size of a packet without building it.
+#[derive(Debug, Default)]
+pub struct CountingWriter {
+    count: u64,
+}
+
+impl CountingWriter {
+    pub fn new() -> Self {
+        Self { count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use crate::errors::Error;
     use crate::io::MAX_VARUINT32;
 
+    use super::accumulate_varuint32;
+    use super::borrow_key_value_pair;
+    use super::CountingReader;
+    use super::CountingWriter;
     use super::Reader;
     use super::VarUint32Size;
     use super::Writer;
-    use std::io::Cursor;
+    use std::io::{self, Cursor};
 
     trait HelperWriter {
         fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error>;
@@ -434,6 +605,30 @@ mod tests {
                 "VarUint32 reader did not return an error(underflow - EOF) for zero bytes input buffer"
             );
         }
+        {
+            // the largest encodings of every length, the four byte one is
+            // MAX_VARUINT32 and a fifth byte is rejected instead of wrapping
+            for (buf, expected) in [
+                (vec![0x7F], 127),
+                (vec![0xFF, 0x7F], 16383),
+                (vec![0xFF, 0xFF, 0x7F], 2097151),
+                (vec![0xFF, 0xFF, 0xFF, 0x7F], MAX_VARUINT32),
+            ] {
+                let mut cur = Cursor::new(buf);
+                assert_eq!(Reader::read_varuint32(&mut cur), Ok(expected));
+            }
+            let mut cur = Cursor::new(vec![0xFF, 0xFF, 0xFF, 0xFF, 0x7F]);
+            let result = Reader::read_varuint32(&mut cur);
+            assert_eq!(result, Err(Error::InvalidVarUint32Length(268435456)));
+
+            // no four byte input overflows, the accumulation itself reports
+            // an overflow instead of wrapping
+            let max = accumulate_varuint32(2097151, 0x7F, 128 * 128 * 128);
+            assert_eq!(max, Some(MAX_VARUINT32));
+            assert_eq!(accumulate_varuint32(u32::MAX, 0x01, 1), None);
+            assert_eq!(accumulate_varuint32(0, 0x7F, 128 * 128 * 128 * 128), None);
+            assert_eq!(accumulate_varuint32(u32::MAX - 1, 0x81, 1), Some(u32::MAX));
+        }
         {
             let mut cur = Cursor::new(vec![0x80, 0x80, 0x80]);
             let result = Reader::read_varuint32(&mut cur);
@@ -509,4 +704,150 @@ mod tests {
             test_char(c);
         }
     }
+
+    #[test]
+    fn test_read_until() {
+        fn read_filter(r: &mut Cursor<Vec<u8>>) -> Result<(u32, (String, u8)), Error> {
+            let filter = r.read_utf8_string()?;
+            let options = r.read_u8()?;
+            Ok((filter.len() as u32 + 3, (filter, options)))
+        }
+
+        let buf = vec![
+            0x00, 0x03, b'a', b'/', b'b', 0x01, // a/b, QoS 1
+            0x00, 0x01, b'#', 0x02, // #, QoS 2
+        ];
+
+        // the list exactly consumes the budget
+        let mut cur = Cursor::new(buf.clone());
+        let mut remaining = buf.len() as u32;
+        let result = cur.read_until(&mut remaining, read_filter);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(
+            result.unwrap(),
+            vec![("a/b".to_string(), 1), ("#".to_string(), 2)]
+        );
+        assert_eq!(remaining, 0);
+
+        // the last item runs past the budget
+        let mut cur = Cursor::new(buf.clone());
+        let mut remaining = buf.len() as u32 - 1;
+        let result = cur.read_until(&mut remaining, read_filter);
+        assert!(matches!(result, Err(Error::MalformedPacket)));
+
+        // nothing to read
+        let mut cur = Cursor::new(buf);
+        let mut remaining = 0;
+        let result = cur.read_until(&mut remaining, read_filter);
+        assert!(result.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_array() {
+        let mut cur = Cursor::new(vec![0x00, 0x04, b'M', b'Q', b'T', b'T']);
+        assert_eq!(Reader::read_array::<2>(&mut cur).unwrap(), [0x00, 0x04]);
+        assert_eq!(&Reader::read_array::<4>(&mut cur).unwrap(), b"MQTT");
+        assert_eq!(Reader::read_array::<0>(&mut cur).unwrap(), []);
+        assert_eq!(
+            Reader::read_array::<1>(&mut cur),
+            Err(Error::MalformedPacket)
+        );
+    }
+
+    #[test]
+    fn test_read_vec() {
+        let mut cur = Cursor::new(vec![0x01, 0x02, 0x03]);
+        assert_eq!(cur.read_vec(2).unwrap(), vec![0x01, 0x02]);
+        assert!(cur.read_vec(0).unwrap().is_empty());
+        assert_eq!(cur.read_vec(2), Err(Error::MalformedPacket));
+
+        // rejected before the buffer is allocated
+        let mut cur = Cursor::new(vec![]);
+        let n = MAX_VARUINT32 as usize + 1;
+        assert_eq!(cur.read_vec(n), Err(Error::LengthTooLarge(n)));
+        assert_eq!(
+            cur.read_vec(usize::MAX),
+            Err(Error::LengthTooLarge(usize::MAX))
+        );
+
+        // a large declared length on a short stream fails once the stream
+        // ends, the reader is never handed a buffer of the declared size
+        struct ShortReader {
+            data: Cursor<Vec<u8>>,
+            largest_buf: usize,
+        }
+        impl io::Read for ShortReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.largest_buf = self.largest_buf.max(buf.len());
+                self.data.read(buf)
+            }
+        }
+        let mut r = ShortReader {
+            data: Cursor::new(vec![0x01, 0x02, 0x03]),
+            largest_buf: 0,
+        };
+        assert_eq!(
+            r.read_vec(MAX_VARUINT32 as usize),
+            Err(Error::MalformedPacket)
+        );
+        assert!(r.largest_buf < 64 * 1024, "{}", r.largest_buf);
+    }
+
+    #[test]
+    fn test_counting_reader() {
+        let mut cur = Cursor::new(vec![0x00, 0x02, b'a', b'b', 0x7F, 0xFF]);
+        let mut r = CountingReader::new(&mut cur);
+        assert_eq!(r.read_utf8_string().unwrap(), "ab");
+        assert_eq!(r.count(), 4);
+        assert_eq!(r.read_varuint32().unwrap(), 0x7F);
+        assert_eq!(r.count(), 5);
+        assert!(r.read_u16().is_err());
+        assert_eq!(cur.position(), 6);
+    }
+
+    #[test]
+    fn test_counting_writer() {
+        let mut w = CountingWriter::new();
+        assert_eq!(w.count(), 0);
+        assert!(w.write_u16(1).is_ok());
+        assert!(w.write_utf8_string("MQTT").is_ok());
+        assert!(w.write_varuint32(MAX_VARUINT32).is_ok());
+        assert_eq!(w.count(), 2 + 6 + 4);
+    }
+
+    #[test]
+    fn test_borrow_key_value_pair() {
+        let buf = vec![0x00, 0x01, b'k', 0x00, 0x02, b'v', b'1', 0xFF];
+        let ((key, value), consumed) = borrow_key_value_pair(&buf).unwrap();
+        assert_eq!((key, value), ("k", "v1"));
+        assert_eq!(consumed, 7);
+        // the pair points into the buffer
+        assert_eq!(key.as_ptr(), buf[2..].as_ptr());
+        assert_eq!(value.as_ptr(), buf[5..].as_ptr());
+
+        // the owned reader decodes the same pair
+        let mut cur = Cursor::new(buf.clone());
+        assert_eq!(
+            cur.read_key_value_pair().unwrap(),
+            (key.to_string(), value.to_string())
+        );
+
+        // truncated and invalid strings
+        assert_eq!(
+            borrow_key_value_pair(&buf[..6]),
+            Err(Error::MalformedPacket)
+        );
+        assert_eq!(
+            borrow_key_value_pair(&buf[..1]),
+            Err(Error::MalformedPacket)
+        );
+        assert_eq!(
+            borrow_key_value_pair(&[0x00, 0x01, 0x01, 0x00, 0x00]),
+            Err(Error::InvalidUTF8String)
+        );
+        assert_eq!(
+            borrow_key_value_pair(&[0x00, 0x00, 0x00, 0x01, 0xFF]),
+            Err(Error::InvalidUTF8String)
+        );
+    }
 }
@@ -1,5 +1,6 @@
 pub mod errors;
 pub mod io;
+pub mod options;
 pub mod properties;
 
 #[macro_use]
@@ -0,0 +1,30 @@
+// DecodeOptions controls how strictly incoming packets are checked while
+// decoding. The default is strict, which is what the MQTTv5 conformance
+// requires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeOptions {
+    // when set, reserved bits that are not set to their specified value are a
+    // malformed packet, otherwise they are ignored
+    pub strict_reserved_bits: bool,
+    // the maximum number of user properties in a property block, a block
+    // with more is a malformed packet
+    pub max_user_properties: usize,
+    // the maximum length of the authentication data, a longer one is a
+    // malformed packet
+    pub max_auth_data: usize,
+    // when set, a property identifier must be followed by at least the
+    // smallest value of its type within the property length, otherwise the
+    // packet is malformed before anything past the block is read
+    pub check_property_min_size: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            strict_reserved_bits: true,
+            max_user_properties: usize::MAX,
+            max_auth_data: usize::MAX,
+            check_property_min_size: true,
+        }
+    }
+}
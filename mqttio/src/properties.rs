@@ -1,9 +1,15 @@
 use core::fmt;
 
-use crate::{errors::Error, io::Reader, io::VarUint32Size, io::Writer};
+use std::io::Cursor;
+
+use crate::{errors::Error, io::Reader, io::VarUint32Size, io::Writer, io::MAX_VARUINT32};
+
+// RawProperty is a property identifier with its encoded value, used to carry
+// properties that a property struct does not model
+pub type RawProperty = (u32, Vec<u8>);
 
 enum_from_primitive! {
-    #[derive(Debug, Copy, Clone)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub enum PropertyID {
         PayloadFormatIndicator = 0x01,
         MessageExpiryInterval = 0x02,
@@ -69,82 +75,234 @@ impl PropertyID {
     }
 }
 
+// PropertyWireType is the data type a property value is encoded with.
+// MQTT 2.2.2.2
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PropertyWireType {
+    Byte,
+    TwoByte,
+    FourByte,
+    VarUint32,
+    String,
+    Binary,
+    StringPair,
+}
+
+impl PropertyID {
+    // all lists every property identifier in increasing order
+    pub fn all() -> &'static [PropertyID] {
+        &[
+            PropertyID::PayloadFormatIndicator,
+            PropertyID::MessageExpiryInterval,
+            PropertyID::ContentType,
+            PropertyID::ResponseTopic,
+            PropertyID::CorrelationData,
+            PropertyID::SubscriptionIdentifier,
+            PropertyID::SessionExpiryInterval,
+            PropertyID::AssignedClientIdentifier,
+            PropertyID::ServerKeepAlive,
+            PropertyID::AuthenticationMethod,
+            PropertyID::AuthenticationData,
+            PropertyID::RequestProblemInfo,
+            PropertyID::WillDelayInterval,
+            PropertyID::RequestResponseInfo,
+            PropertyID::ResponseInformation,
+            PropertyID::ServerReference,
+            PropertyID::ReasonString,
+            PropertyID::ReceiveMaximum,
+            PropertyID::TopicAliasMaximum,
+            PropertyID::TopicAlias,
+            PropertyID::MaximumQoS,
+            PropertyID::RetainAvailable,
+            PropertyID::UserProperty,
+            PropertyID::MaximumPacketSize,
+            PropertyID::WildcardSubscriptionAvailable,
+            PropertyID::SubscriptionIdentifierAvailable,
+            PropertyID::SharedSubscriptionAvailable,
+        ]
+    }
+
+    pub fn wire_type(&self) -> PropertyWireType {
+        match self {
+            PropertyID::PayloadFormatIndicator
+            | PropertyID::RequestProblemInfo
+            | PropertyID::RequestResponseInfo
+            | PropertyID::MaximumQoS
+            | PropertyID::RetainAvailable
+            | PropertyID::WildcardSubscriptionAvailable
+            | PropertyID::SubscriptionIdentifierAvailable
+            | PropertyID::SharedSubscriptionAvailable => PropertyWireType::Byte,
+            PropertyID::ServerKeepAlive
+            | PropertyID::ReceiveMaximum
+            | PropertyID::TopicAliasMaximum
+            | PropertyID::TopicAlias => PropertyWireType::TwoByte,
+            PropertyID::MessageExpiryInterval
+            | PropertyID::SessionExpiryInterval
+            | PropertyID::WillDelayInterval
+            | PropertyID::MaximumPacketSize => PropertyWireType::FourByte,
+            PropertyID::SubscriptionIdentifier => PropertyWireType::VarUint32,
+            PropertyID::ContentType
+            | PropertyID::ResponseTopic
+            | PropertyID::AssignedClientIdentifier
+            | PropertyID::AuthenticationMethod
+            | PropertyID::ResponseInformation
+            | PropertyID::ServerReference
+            | PropertyID::ReasonString => PropertyWireType::String,
+            PropertyID::CorrelationData | PropertyID::AuthenticationData => {
+                PropertyWireType::Binary
+            }
+            PropertyID::UserProperty => PropertyWireType::StringPair,
+        }
+    }
+}
+
 impl fmt::Display for PropertyID {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "PropertyID::{:?}", self)
     }
 }
 
-macro_rules! property_size {
+macro_rules! property_size_with_id {
     ($name:tt, $t:ty, varuint32) => {
-        pub fn $name(value: &Option<$t>) -> u32
-        where
-            $t: Sized,
-        {
-            if value.is_some() {
-                return VarUint32Size::size(value.unwrap()) + 1; // 1 for Property ID
-            }
-            return 0;
+        pub fn $name(value: $t) -> u32 {
+            VarUint32Size::size(value) + 1 // 1 for Property ID
+        }
+    };
+    ($name:tt, $t:ty, by_len) => {
+        pub fn $name(value: &$t) -> u32 {
+            value.len() as u32 + 3 // 1 for Property ID + 2 length (u16)
         }
     };
     ($name:tt, $t:ty, $size:expr) => {
+        pub fn $name(_value: $t) -> u32 {
+            $size + 1 // 1 for Property ID
+        }
+    };
+}
+
+macro_rules! property_size {
+    ($name:tt, $t:ty, $with_id:ident) => {
         pub fn $name(value: &Option<$t>) -> u32
         where
             $t: Sized,
         {
-            if value.is_some() {
-                return $size + 1; // 1 for Property ID
+            match value {
+                Some(v) => Self::$with_id(*v),
+                None => 0,
             }
-            return 0;
         }
     };
 }
 
 macro_rules! property_size_by_len {
-    ($name:tt, $t:ty) => {
+    ($name:tt, $t:ty, $with_id:ident) => {
         pub fn $name(value: &$t) -> u32 {
             if !value.is_empty() {
-                return value.len() as u32 + 3; // 1 for Property ID + 2 length (u16)
+                return Self::$with_id(value);
             }
             return 0;
         }
     };
 }
 
+// PropertySize calculates the number of bytes a property occupies in the
+// property block, including the property identifier. The of_*_with_id
+// functions give the size of a single property value, the from_* functions
+// the size of an optional (or possibly empty) field as accumulated by the
+// generated len.
 pub struct PropertySize {}
 
 impl PropertySize {
-    property_size!(from_bool, bool, 1);
-    property_size!(from_u8, u8, 1);
-    property_size!(from_u16, u16, 2);
-    property_size!(from_u32, u32, 4);
+    property_size_with_id!(of_bool_with_id, bool, 1);
+    property_size_with_id!(of_u8_with_id, u8, 1);
+    property_size_with_id!(of_u16_with_id, u16, 2);
+    property_size_with_id!(of_u32_with_id, u32, 4);
+    property_size_with_id!(of_varuint32_with_id, u32, varuint32);
+    property_size_with_id!(of_utf8_string_with_id, str, by_len);
+    property_size_with_id!(of_binary_data_with_id, [u8], by_len);
 
-    property_size_by_len!(from_utf8_string, str);
-    property_size_by_len!(from_binary_data, Vec<u8>);
+    pub fn of_utf8_string_pair_with_id(key: &str, value: &str) -> u32 {
+        (key.len() + value.len()) as u32 + 5 // 1 for Property ID + 2 * 2 length (u16)
+    }
 
-    property_size!(from_varuint32, u32, varuint32);
+    property_size!(from_bool, bool, of_bool_with_id);
+    property_size!(from_u8, u8, of_u8_with_id);
+    property_size!(from_u16, u16, of_u16_with_id);
+    property_size!(from_u32, u32, of_u32_with_id);
 
-    pub fn from_varuint32_array(arr: &[u32]) -> usize {
-        if arr.len() == 0 {
-            return 0;
-        }
-        let mut property_len: usize = 0;
-        for d in arr {
-            let n = VarUint32Size::size(*d);
-            property_len += usize::try_from(n).unwrap() + 1; // 1 for Property ID
-        }
-        return property_len;
+    property_size_by_len!(from_utf8_string, str, of_utf8_string_with_id);
+    property_size_by_len!(from_binary_data, Vec<u8>, of_binary_data_with_id);
+
+    property_size!(from_varuint32, u32, of_varuint32_with_id);
+
+    // every value is written with its own property id, see
+    // PropertyWriter::from_varuint32_array
+    pub fn from_varuint32_array(arr: &[u32]) -> u32 {
+        arr.iter()
+            .map(|v| Self::of_varuint32_with_id(*v))
+            .fold(0, u32::saturating_add)
+    }
+
+    // min_with_id is the size of the smallest property of the given id, e.g.
+    // an empty string or a single byte variable integer
+    pub fn min_with_id(id: PropertyID) -> u32 {
+        let value_size = match id.wire_type() {
+            PropertyWireType::Byte | PropertyWireType::VarUint32 => 1,
+            PropertyWireType::TwoByte | PropertyWireType::String | PropertyWireType::Binary => 2,
+            PropertyWireType::FourByte | PropertyWireType::StringPair => 4,
+        };
+        VarUint32Size::size(id as u32) + value_size
+    }
+
+    pub fn of_raw_with_id(id: u32, raw: &[u8]) -> u32 {
+        VarUint32Size::size(id) + raw.len() as u32
     }
 
+    // the sums of from_raw, from_utf8_string_pair and from_varuint32_array
+    // saturate, any saturated size is rejected by add
+    pub fn from_raw(arr: &[RawProperty]) -> u32 {
+        arr.iter()
+            .map(|(id, raw)| Self::of_raw_with_id(*id, raw))
+            .fold(0, u32::saturating_add)
+    }
+
+    // every pair is written with its own property id, see
+    // PropertyWriter::from_utf8_pair
     pub fn from_utf8_string_pair(arr: &[(String, String)]) -> u32 {
-        if arr.len() == 0 {
-            return 0;
-        }
-        let mut property_len: usize = 0;
-        for d in arr {
-            property_len += 4 + d.0.len() + d.1.len();
+        arr.iter()
+            .map(|(k, v)| Self::of_utf8_string_pair_with_id(k, v))
+            .fold(0, u32::saturating_add)
+    }
+
+    // add accumulates the size of a property into the length of a property
+    // block, which must fit in a variable byte integer.
+    pub fn add(property_len: u32, size: u32) -> Result<u32, Error> {
+        match property_len.checked_add(size) {
+            Some(v) if v <= MAX_VARUINT32 => Ok(v),
+            _ => Err(Error::PropertyLengthTooLarge),
         }
-        return property_len as u32;
+    }
+}
+
+// Properties is implemented by the property structs generated with
+// #[derive(IOOperations)] so that generic code can read, write and size a
+// property block. The generated inherent read, write and len are used for the
+// implementation.
+pub trait Properties: Sized {
+    type Error;
+
+    fn read<R: Reader>(r: &mut R) -> Result<Option<Self>, Self::Error>;
+    fn read_with_limit<R: Reader>(r: &mut R, limit: u32) -> Result<Option<Self>, Self::Error>;
+    fn write<W: Writer>(&self, w: &mut W) -> Result<(), Self::Error>;
+    fn property_length(&self) -> Result<u32, Self::Error>;
+
+    // write_block writes the property length followed by the properties
+    fn write_block<W: Writer>(&self, w: &mut W) -> Result<(), Self::Error>
+    where
+        Self::Error: From<Error>,
+    {
+        w.write_varuint32(self.property_length()?)?;
+        self.write(w)
     }
 }
 
@@ -173,11 +331,31 @@ impl PropertyReader {
     property_reader_fn!(to_varuint32, u32, read_varuint32);
     property_reader_fn!(to_utf8_string, String, read_utf8_string, no_option);
     property_reader_fn!(to_binary_data, Vec<u8>, read_binary, no_option);
+
     pub fn read_utf8_pair<R: Reader>(r: &mut R) -> Result<(String, String), Error> {
         let key = r.read_utf8_string()?;
         let value = r.read_utf8_string()?;
         return Ok((key, value));
     }
+
+    // to_raw reads the value of the property id and returns its encoded bytes,
+    // the value is decoded so that malformed values are still rejected
+    pub fn to_raw<R: Reader>(r: &mut R, id: PropertyID) -> Result<Vec<u8>, Error> {
+        let mut raw = Cursor::new(Vec::new());
+        match id.wire_type() {
+            PropertyWireType::Byte => raw.write_u8(r.read_u8()?)?,
+            PropertyWireType::TwoByte => raw.write_u16(r.read_u16()?)?,
+            PropertyWireType::FourByte => raw.write_u32(r.read_u32()?)?,
+            PropertyWireType::VarUint32 => raw.write_varuint32(r.read_varuint32()?)?,
+            PropertyWireType::String => raw.write_utf8_string(&r.read_utf8_string()?)?,
+            PropertyWireType::Binary => raw.write_binary(&r.read_binary()?)?,
+            PropertyWireType::StringPair => {
+                let (key, value) = r.read_key_value_pair()?;
+                raw.write_key_value_pair(&key, &value)?
+            }
+        }
+        Ok(raw.into_inner())
+    }
 }
 
 // PropertyWriter write the property when the value is not empty
@@ -227,15 +405,37 @@ impl PropertyWriter {
         }
         return Ok(());
     }
+
+    pub fn from_varuint32_array<W: Writer>(
+        w: &mut W,
+        id: PropertyID,
+        values: &[u32],
+    ) -> Result<(), Error> {
+        for v in values {
+            w.write_varuint32(id as u32)?;
+            w.write_varuint32(*v)?;
+        }
+        Ok(())
+    }
+
+    pub fn from_raw<W: Writer>(w: &mut W, values: &[RawProperty]) -> Result<(), Error> {
+        for (id, raw) in values {
+            w.write_varuint32(*id)?;
+            w.write_internal(raw)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use crate::errors::Error;
+    use crate::io::MAX_VARUINT32;
     use crate::properties::{PropertyReader, PropertySize, PropertyWriter};
 
-    use super::PropertyID;
+    use super::{PropertyID, PropertyWireType};
 
     macro_rules! property_reader_helper {
         ($name:tt, $t:ty, $read_method:ident) => {
@@ -316,7 +516,98 @@ mod tests {
                 ("hello".to_string(), "world".to_string()),
                 ("hello".to_string(), "world".to_string())
             ]),
-            28
+            30
+        );
+        assert_eq!(PropertySize::from_utf8_string_pair(&[]), 0);
+
+        let pairs = [
+            ("hello".to_string(), "world".to_string()),
+            ("".to_string(), "".to_string()),
+            ("key".to_string(), "a longer value".to_string()),
+        ];
+        let mut cur = Cursor::new(Vec::new());
+        let result = PropertyWriter::from_utf8_pair(&mut cur, PropertyID::UserProperty, &pairs);
+        assert!(result.is_ok());
+        assert_eq!(
+            PropertySize::from_utf8_string_pair(&pairs),
+            cur.get_ref().len() as u32
+        );
+    }
+
+    #[test]
+    fn test_property_size_add() {
+        assert_eq!(PropertySize::add(0, 5), Ok(5));
+        assert_eq!(PropertySize::add(MAX_VARUINT32 - 5, 5), Ok(MAX_VARUINT32));
+        assert_eq!(
+            PropertySize::add(MAX_VARUINT32 - 5, 6),
+            Err(Error::PropertyLengthTooLarge)
+        );
+        assert_eq!(
+            PropertySize::add(u32::MAX, 1),
+            Err(Error::PropertyLengthTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_property_size_with_id() {
+        fn written_len<F>(write: F) -> u32
+        where
+            F: FnOnce(&mut Cursor<Vec<u8>>) -> Result<(), Error>,
+        {
+            let mut cur = Cursor::new(Vec::new());
+            assert!(write(&mut cur).is_ok());
+            cur.get_ref().len() as u32
+        }
+
+        let id = PropertyID::ContentType;
+        assert_eq!(PropertySize::of_bool_with_id(true), 2);
+        assert_eq!(
+            PropertySize::of_bool_with_id(true),
+            written_len(|w| PropertyWriter::from_bool(w, id, &Some(true)))
+        );
+        assert_eq!(PropertySize::of_u8_with_id(8), 2);
+        assert_eq!(
+            PropertySize::of_u8_with_id(8),
+            written_len(|w| PropertyWriter::from_u8(w, id, &Some(8)))
+        );
+        assert_eq!(PropertySize::of_u16_with_id(128), 3);
+        assert_eq!(
+            PropertySize::of_u16_with_id(128),
+            written_len(|w| PropertyWriter::from_u16(w, id, &Some(128)))
+        );
+        assert_eq!(PropertySize::of_u32_with_id(1024), 5);
+        assert_eq!(
+            PropertySize::of_u32_with_id(1024),
+            written_len(|w| PropertyWriter::from_u32(w, id, &Some(1024)))
+        );
+        for (v, size) in [(0, 2), (127, 2), (128, 3), (16384, 4), (2097152, 5)] {
+            assert_eq!(PropertySize::of_varuint32_with_id(v), size);
+            assert_eq!(
+                PropertySize::of_varuint32_with_id(v),
+                written_len(|w| PropertyWriter::from_varuint32(w, id, &Some(v)))
+            );
+        }
+        assert_eq!(PropertySize::of_utf8_string_with_id("hello"), 8);
+        assert_eq!(
+            PropertySize::of_utf8_string_with_id("hello"),
+            written_len(|w| PropertyWriter::from_utf8_string(w, id, &"hello".to_string()))
+        );
+        assert_eq!(PropertySize::of_binary_data_with_id(&[0x01, 0x02]), 5);
+        assert_eq!(
+            PropertySize::of_binary_data_with_id(&[0x01, 0x02]),
+            written_len(|w| PropertyWriter::from_binary_data(w, id, &vec![0x01, 0x02]))
+        );
+        assert_eq!(
+            PropertySize::of_utf8_string_pair_with_id("hello", "world"),
+            15
+        );
+        assert_eq!(
+            PropertySize::of_utf8_string_pair_with_id("hello", "world"),
+            written_len(|w| PropertyWriter::from_utf8_pair(
+                w,
+                id,
+                &[("hello".to_string(), "world".to_string())]
+            ))
         );
     }
 
@@ -344,6 +635,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_property_raw() {
+        let data: [(PropertyID, &[u8]); 5] = [
+            (PropertyID::MaximumQoS, &[0x01]),
+            (PropertyID::TopicAlias, &[0x00, 0x0A]),
+            (PropertyID::SubscriptionIdentifier, &[0x80, 0x01]),
+            (PropertyID::ReasonString, &[0x00, 0x02, b'o', b'k']),
+            (
+                PropertyID::UserProperty,
+                &[0x00, 0x01, b'k', 0x00, 0x01, b'v'],
+            ),
+        ];
+        for (id, bytes) in data {
+            let mut cur = Cursor::new(bytes);
+            let result = PropertyReader::to_raw(&mut cur, id);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            let raw = result.unwrap();
+            assert_eq!(raw, bytes);
+
+            let values = [(id as u32, raw)];
+            let mut cur = Cursor::new(Vec::new());
+            assert!(PropertyWriter::from_raw(&mut cur, &values).is_ok());
+            assert_eq!(cur.get_ref().len() as u32, PropertySize::from_raw(&values));
+            assert_eq!(cur.get_ref()[0], id as u8);
+            assert_eq!(&cur.get_ref()[1..], bytes);
+        }
+
+        // malformed values are rejected
+        let mut cur = Cursor::new([0x00, 0x01, 0x01]);
+        assert!(PropertyReader::to_raw(&mut cur, PropertyID::ReasonString).is_err());
+    }
+
     fn concat_u8(first: &[u8], second: &[u8]) -> Vec<u8> {
         [first, second].concat()
     }
@@ -396,4 +719,52 @@ mod tests {
             assert_eq!(cur.get_ref(), &data);
         }
     }
+
+    #[test]
+    fn test_wire_type() {
+        assert_eq!(
+            PropertyID::UserProperty.wire_type(),
+            PropertyWireType::StringPair
+        );
+        assert_eq!(
+            PropertyID::MaximumPacketSize.wire_type(),
+            PropertyWireType::FourByte
+        );
+        assert_eq!(
+            PropertyID::TopicAlias.wire_type(),
+            PropertyWireType::TwoByte
+        );
+        assert_eq!(PropertyID::MaximumQoS.wire_type(), PropertyWireType::Byte);
+        assert_eq!(
+            PropertyID::SubscriptionIdentifier.wire_type(),
+            PropertyWireType::VarUint32
+        );
+        assert_eq!(
+            PropertyID::ReasonString.wire_type(),
+            PropertyWireType::String
+        );
+        assert_eq!(
+            PropertyID::CorrelationData.wire_type(),
+            PropertyWireType::Binary
+        );
+
+        // the smallest property follows the wire type
+        assert_eq!(PropertySize::min_with_id(PropertyID::MaximumQoS), 2);
+        assert_eq!(
+            PropertySize::min_with_id(PropertyID::SubscriptionIdentifier),
+            2
+        );
+        assert_eq!(PropertySize::min_with_id(PropertyID::TopicAlias), 3);
+        assert_eq!(PropertySize::min_with_id(PropertyID::ReasonString), 3);
+        assert_eq!(PropertySize::min_with_id(PropertyID::CorrelationData), 3);
+        assert_eq!(PropertySize::min_with_id(PropertyID::MaximumPacketSize), 5);
+        assert_eq!(PropertySize::min_with_id(PropertyID::UserProperty), 5);
+
+        // every identifier once, in increasing order
+        let all = PropertyID::all();
+        assert_eq!(all.len(), 27);
+        assert!(all.windows(2).all(|w| (w[0] as u32) < (w[1] as u32)));
+        assert_eq!(all[0], PropertyID::PayloadFormatIndicator);
+        assert_eq!(all[26], PropertyID::SharedSubscriptionAvailable);
+    }
 }
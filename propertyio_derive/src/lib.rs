@@ -49,29 +49,55 @@ impl FromStr for DataType {
 }
 
 fn get_reader_match_expr(
-    ty: &Ident,
+    ty_str: &str,
     generic_ty: &str,
     prop_id_str: &str,
     field_ident: &Ident,
+    max_len: Option<&Ident>,
 ) -> Result<TokenStream2, LexError> {
-    let ty_str = ty.to_string();
-    let match_expr = match &*ty_str {
+    if max_len.is_some() && !(ty_str == "Vec" && generic_ty == "u8") {
+        panic!(
+            "max_len is only supported for Vec<u8>, found {}<{}>",
+            ty_str, generic_ty
+        );
+    }
+    let match_expr = match ty_str {
         "String" => quote! {
             props.#field_ident = PropertyReader::to_utf8_string(r)?;
-            property_len -= PropertySize::from_utf8_string(&props.#field_ident);
+            // an empty string still takes the id and the length on the wire
+            let size = PropertySize::of_utf8_string_with_id(&props.#field_ident);
         },
         "Vec" => match generic_ty {
+            // binary data marked with max_len, e.g. the authentication data,
+            // has its declared length checked against the named limit of the
+            // decode options before it is read
+            "u8" if max_len.is_some() => quote! {
+                let data_len = usize::from(r.read_u16()?);
+                if data_len > options.#max_len {
+                    return Err(Error::MalformedPacket);
+                }
+                props.#field_ident = r.read_vec(data_len)?;
+                let size = PropertySize::of_binary_data_with_id(&props.#field_ident);
+            },
             "u8" => quote! {
                 props.#field_ident = PropertyReader::to_binary_data(r)?;
-                property_len -= PropertySize::from_binary_data(&props.#field_ident);
+                let size = PropertySize::of_binary_data_with_id(&props.#field_ident);
+            },
+            "varuint32" => quote! {
+                let value = r.read_varuint32()?;
+                let size = PropertySize::of_varuint32_with_id(value);
+                props.#field_ident.push(value);
             },
             "KeyValuePair" => quote! {
+                if props.#field_ident.len() >= options.max_user_properties {
+                    return Err(Error::MalformedPacket);
+                }
                 let value = r.read_key_value_pair()?;
+                let size = PropertySize::of_utf8_string_pair_with_id(&value.0, &value.1);
                 props.#field_ident.push(value);
-                property_len -= PropertySize::from_utf8_string_pair(&props.#field_ident);
             },
             _ => panic!(
-                "unexpected type found - should be Vec<u8> or Vec<KeyValuePair>, found {}<{}>",
+                "unexpected type found - should be Vec<u8>, Vec<KeyValuePair> or a varuint32 Vec<u32>, found {}<{}>",
                 ty_str, generic_ty
             ),
         },
@@ -81,28 +107,29 @@ fn get_reader_match_expr(
                 TokenStream2::from_str(&format!("PropertySize::from_{}", ty_str))?;
             quote! {
                 props.#field_ident = #quote_fn(r)?;
-                property_len -= #quote_property_len_fn(&props.#field_ident);
+                let size = #quote_property_len_fn(&props.#field_ident);
             }
         }
     };
 
+    // a property running past the declared property length is malformed
     let prop_id_stream = TokenStream2::from_str(prop_id_str)?;
     return Ok(quote! {
         Some(#prop_id_stream) => {
             #match_expr
+            property_len = property_len.checked_sub(size).ok_or(Error::MalformedPacket)?;
         },
     });
 }
 
 fn get_writer_match_expr(
-    ty: &Ident,
+    ty_str: &str,
     generic_ty: &str,
     prop_id_str: &str,
     field_ident: &Ident,
 ) -> Result<TokenStream2, LexError> {
     let prop_id_stream = TokenStream2::from_str(prop_id_str)?;
-    let ty_str = ty.to_string();
-    let match_expr = match &*ty_str {
+    let match_expr = match ty_str {
         "String" => quote! {
             PropertyWriter::from_utf8_string(w, #prop_id_stream, &self.#field_ident)?;
         },
@@ -113,8 +140,11 @@ fn get_writer_match_expr(
             "KeyValuePair" => quote! {
                 PropertyWriter::from_utf8_pair(w, #prop_id_stream, &self.#field_ident)?;
             },
+            "varuint32" => quote! {
+                PropertyWriter::from_varuint32_array(w, #prop_id_stream, &self.#field_ident)?;
+            },
             _ => panic!(
-                "unexpected type found - should be Vec<u8> or Vec<KeyValuePair>, found {}<{}>",
+                "unexpected type found - should be Vec<u8>, Vec<KeyValuePair> or a varuint32 Vec<u32>, found {}<{}>",
                 ty_str, generic_ty
             ),
         },
@@ -131,27 +161,29 @@ fn get_writer_match_expr(
     })
 }
 
-fn get_len_expr(ty: &Ident, generic_ty: &str, field_ident: &Ident) -> TokenStream2 {
-    let ty_str = ty.to_string();
-    match &*ty_str {
+fn get_len_expr(ty_str: &str, generic_ty: &str, field_ident: &Ident) -> TokenStream2 {
+    match ty_str {
         "String" => quote! {
-            property_len += PropertySize::from_utf8_string(&self.#field_ident);
+            property_len = PropertySize::add(property_len, PropertySize::from_utf8_string(&self.#field_ident))?;
         },
         "Vec" => match generic_ty {
             "u8" => quote! {
-                property_len += PropertySize::from_binary_data(&self.#field_ident);
+                property_len = PropertySize::add(property_len, PropertySize::from_binary_data(&self.#field_ident))?;
             },
             "KeyValuePair" => quote! {
-                property_len += PropertySize::from_utf8_string_pair(&self.#field_ident);
+                property_len = PropertySize::add(property_len, PropertySize::from_utf8_string_pair(&self.#field_ident))?;
+            },
+            "varuint32" => quote! {
+                property_len = PropertySize::add(property_len, PropertySize::from_varuint32_array(&self.#field_ident))?;
             },
             _ => panic!(
-                "unexpected type found - should be Vec<u8> or Vec<KeyValuePair>, found {}<{}>",
+                "unexpected type found - should be Vec<u8>, Vec<KeyValuePair> or a varuint32 Vec<u32>, found {}<{}>",
                 ty_str, generic_ty
             ),
         },
         _ => {
             let quote_fn = TokenStream2::from_str(&format!(
-                "property_len += PropertySize::from_{}(&self.{});",
+                "property_len = PropertySize::add(property_len, PropertySize::from_{}(&self.{}))?;",
                 ty_str,
                 field_ident.to_string()
             ));
@@ -180,6 +212,7 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
     };
 
     let name = &input.ident;
+    let mut unknown_field: Option<&Ident> = None;
 
     for field in fields {
         let Field {
@@ -228,7 +261,9 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
         }
 
         let mut is_varuint32 = false;
+        let mut is_unknown = false;
         let mut prop_id: Option<String> = None::<String>;
+        let mut max_len: Option<Ident> = None;
         for attribute in attrs {
             if attribute.path().is_ident("ioops") {
                 _ = attribute.parse_nested_meta(|meta| {
@@ -236,6 +271,15 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
                         is_varuint32 = true;
                         return Ok(());
                     }
+                    if meta.path.is_ident("unknown") {
+                        is_unknown = true;
+                        return Ok(());
+                    }
+                    if meta.path.is_ident("max_len") {
+                        let value: syn::LitStr = meta.value()?.parse()?;
+                        max_len = Some(value.parse()?);
+                        return Ok(());
+                    }
                     if meta.path.is_ident("prop_id") {
                         let content: syn::parse::ParseBuffer;
                         parenthesized!(content in meta.input);
@@ -249,13 +293,39 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
             }
         }
 
+        // the field collecting the properties that are not modelled by the
+        // struct, of type Vec<RawProperty>
+        if is_unknown {
+            if unknown_field.is_some() {
+                panic!("only one field can be marked as unknown");
+            }
+            unknown_field = Some(ident);
+            continue;
+        }
+
         if prop_id.is_none() {
             panic!("prop_id not found for the field {}", ident.to_string());
         }
 
+        // a u32 marked with is_varuint32 is encoded as a variable byte
+        // integer, e.g. the Subscription Identifier
+        let mut ty_str = type_ident.unwrap().to_string();
+        if is_varuint32 {
+            if ty_str == "Vec" {
+                generic_arg_type = "varuint32".to_string();
+            } else {
+                ty_str = "varuint32".to_string();
+            }
+        }
+
         let prop_id_str = prop_id.unwrap().to_string();
-        let reader_match_expr =
-            get_reader_match_expr(type_ident.unwrap(), &generic_arg_type, &prop_id_str, ident);
+        let reader_match_expr = get_reader_match_expr(
+            &ty_str,
+            &generic_arg_type,
+            &prop_id_str,
+            ident,
+            max_len.as_ref(),
+        );
         if reader_match_expr.is_err() {
             panic!(
                 "Failed to generate a matcher expression for the reader {}",
@@ -266,7 +336,7 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
         reader_impls.extend(reader_match_expr_ok);
 
         let writer_match_expr =
-            get_writer_match_expr(type_ident.unwrap(), &generic_arg_type, &prop_id_str, ident);
+            get_writer_match_expr(&ty_str, &generic_arg_type, &prop_id_str, ident);
         if writer_match_expr.is_err() {
             panic!(
                 "Failed to generate a matcher expression for the writer {}",
@@ -276,14 +346,92 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
         let writer_match_expr_ok = writer_match_expr.unwrap();
         writer_impls.extend(writer_match_expr_ok);
 
-        len_impls.extend(get_len_expr(type_ident.unwrap(), &generic_arg_type, ident));
+        len_impls.extend(get_len_expr(&ty_str, &generic_arg_type, ident));
+    }
+
+    let mut unknown_reader_arm = TokenStream2::new();
+    let mut reader_fns = quote! {
+        pub fn read<R: Reader>(r: &mut R) -> Result<Option<#name>, Error> {
+            let property_len = r.read_varuint32()?;
+            #name::read_properties(r, property_len, false, &DecodeOptions::default())
+        }
+
+        // read_with_options reads a property block honouring the limits of
+        // the decode options, e.g. the number of user properties.
+        pub fn read_with_options<R: Reader>(
+            r: &mut R,
+            options: &DecodeOptions,
+        ) -> Result<Option<#name>, Error> {
+            let property_len = r.read_varuint32()?;
+            #name::read_properties(r, property_len, false, options)
+        }
+
+        // read_with_limit reads a property block that must fit in the limit
+        // bytes left in the packet, the property length included. A declared
+        // length running past the packet is a malformed packet.
+        pub fn read_with_limit<R: Reader>(r: &mut R, limit: u32) -> Result<Option<#name>, Error> {
+            #name::read_with_limit_and_options(r, limit, &DecodeOptions::default())
+        }
+
+        pub fn read_with_limit_and_options<R: Reader>(
+            r: &mut R,
+            limit: u32,
+            options: &DecodeOptions,
+        ) -> Result<Option<#name>, Error> {
+            let property_len = r.read_varuint32()?;
+            let block_len = VarUint32Size::size(property_len) as u64 + property_len as u64;
+            if block_len > limit as u64 {
+                return Err(Error::MalformedPacket);
+            }
+            #name::read_properties(r, property_len, false, options)
+        }
+
+        // read_with_raw also returns the bytes of the property block as they
+        // were read, the property length included, e.g. for forwarding them
+        // unchanged.
+        pub fn read_with_raw<R: Reader>(r: &mut R) -> Result<(Option<#name>, Vec<u8>), Error> {
+            let mut tee = TeeReader::new(r);
+            let props = #name::read(&mut tee)?;
+            Ok((props, tee.into_consumed()))
+        }
+    };
+    if let Some(unknown) = unknown_field {
+        // a property that is valid but not modelled by the struct is kept as
+        // is when reading with read_allow_unknown, and written back after the
+        // known properties
+        unknown_reader_arm = quote! {
+            Some(p) if allow_unknown => {
+                let raw = PropertyReader::to_raw(r, p)?;
+                let size = PropertySize::of_raw_with_id(id, &raw);
+                property_len = property_len.checked_sub(size).ok_or(Error::MalformedPacket)?;
+                props.#unknown.push((id, raw));
+            },
+        };
+        reader_fns.extend(quote! {
+            pub fn read_allow_unknown<R: Reader>(r: &mut R) -> Result<Option<#name>, Error> {
+                let property_len = r.read_varuint32()?;
+                #name::read_properties(r, property_len, true, &DecodeOptions::default())
+            }
+        });
+        writer_impls.extend(quote! {
+            PropertyWriter::from_raw(w, &self.#unknown)?;
+        });
+        len_impls.extend(quote! {
+            property_len = PropertySize::add(property_len, PropertySize::from_raw(&self.#unknown))?;
+        });
     }
 
     let tokens = quote! {
 
         impl #name {
-            pub fn read<R: Reader>(r: &mut R) -> Result<Option<#name>, Error> {
-                let mut property_len = r.read_varuint32()?;
+            #reader_fns
+
+            fn read_properties<R: Reader>(
+                r: &mut R,
+                mut property_len: u32,
+                allow_unknown: bool,
+                options: &DecodeOptions,
+            ) -> Result<Option<#name>, Error> {
                 if property_len == 0 {
                     return Ok(None);
                 }
@@ -294,8 +442,14 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
                     if property_id.is_none() {
                         return Err(Error::InvalidPropertyID(id));
                     }
+                    if options.check_property_min_size
+                        && property_len < PropertySize::min_with_id(property_id.unwrap())
+                    {
+                        return Err(Error::MalformedPacket);
+                    }
                     match property_id {
                         #reader_impls
+                        #unknown_reader_arm
                         _ => return Err(Error::InvalidPropertyID(id)),
                     }
                 }
@@ -308,10 +462,30 @@ pub fn derive_io_fns(input: TokenStream) -> TokenStream {
                 return Ok(());
             }
 
-            pub fn len(&self) -> u32 {
+            pub fn len(&self) -> Result<u32, Error> {
                 let mut property_len: u32 = 0;
                 #len_impls
-                return property_len;
+                return Ok(property_len);
+            }
+        }
+
+        impl Properties for #name {
+            type Error = Error;
+
+            fn read<R: Reader>(r: &mut R) -> Result<Option<#name>, Error> {
+                #name::read(r)
+            }
+
+            fn read_with_limit<R: Reader>(r: &mut R, limit: u32) -> Result<Option<#name>, Error> {
+                #name::read_with_limit(r, limit)
+            }
+
+            fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+                #name::write(self, w)
+            }
+
+            fn property_length(&self) -> Result<u32, Error> {
+                self.len()
             }
         }
     };
@@ -1,8 +1,9 @@
 use mqttio::errors::Error;
 use propertyio_derive::IOOperations;
 
-use mqttio::io::{Reader, Writer};
-use mqttio::properties::{PropertyID, PropertyReader, PropertySize, PropertyWriter};
+use mqttio::io::{Reader, TeeReader, VarUint32Size, Writer};
+use mqttio::options::DecodeOptions;
+use mqttio::properties::{Properties, PropertyID, PropertyReader, PropertySize, PropertyWriter};
 use num::FromPrimitive;
 
 #[derive(Default, IOOperations)]
@@ -1,3 +1,5 @@
+use crate::packet::packet::ReasonCode;
+
 #[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
 pub enum Error {
     #[error("topic length is too long, max = 65335")]
@@ -6,20 +8,42 @@ pub enum Error {
     InvalidTopic,
     #[error("empty subscription topics are not allowed")]
     EmptySubscriptionTopic,
+    #[error("topic filter '{0}' is included more than once")]
+    DuplicateTopicFilter(String),
     #[error("{0} property must not be included more than once")]
     PropertyAlreadyExists(&'static str),
     #[error("invalid protocol name - found {0}")]
     InvalidProtocolName(String),
+    #[error("invalid protocol name length - expected 4, found {0}")]
+    InvalidProtocolNameLength(u16),
     #[error("invalid protocol version - only version 5 is supported")]
     InvalidProtocolVersion,
     #[error("invalid connect flags - Malformed packet")]
     InvalidConnectFlags,
+    #[error("invalid connect acknowledge flags {0:#010b} - Malformed packet")]
+    InvalidConnackFlags(u8),
     #[error("invalid Will QoS - Malformed packet")]
     InvalidWillQos,
+    #[error("invalid PUBLISH QoS {0}")]
+    InvalidPublishQos(u8),
     #[error("invalid Will QoS flags - Malformed packet")]
     InvalidQosFlags,
     #[error("invalid Will retain flag - Malformed packet")]
     InvalidWillRetain,
+    #[error("Malformed packet")]
+    MalformedPacket,
+    #[error("reserved packet type 0 - Malformed packet")]
+    ReservedPacketType,
+    #[error("invalid fixed header flags {1:#06b} for {0} - Malformed packet")]
+    InvalidFixedHeaderFlags(&'static str, u8),
+    #[error("protocol error - {0}")]
+    ProtocolError(&'static str),
+    #[error("keep alive of {0} seconds is more than the maximum of 65535")]
+    InvalidKeepAlive(u64),
+    #[error("maximum packet size {0} is below the minimum of {1}")]
+    MaximumPacketSizeTooSmall(u32, u32),
+    #[error("packet of {0} bytes is more than the maximum packet size {1}")]
+    PacketTooLarge(u64, u32),
     #[error("invalid property id - Malformed packet")]
     InvalidPropertyID(u32),
     #[error("CONNECT - Will properties contains wrong property identifier {0}")]
@@ -30,12 +54,54 @@ pub enum Error {
     InvalidRemaningLength(core::num::TryFromIntError),
 }
 
+// UNSUPPORTED_PROTOCOL_VERSION is a CONNACK only reason code
+const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+
+impl Error {
+    // reason_code returns the MQTT reason code to report the error with in a
+    // CONNACK or a DISCONNECT. The errors of the io layer are mapped by
+    // mqttio.
+    pub fn reason_code(&self) -> u8 {
+        let reason_code = match self {
+            Error::IOError(e) => return e.reason_code(),
+            Error::InvalidProtocolName(_)
+            | Error::InvalidProtocolNameLength(_)
+            | Error::InvalidProtocolVersion => return UNSUPPORTED_PROTOCOL_VERSION,
+            Error::TopicLenTooLong
+            | Error::InvalidConnectFlags
+            | Error::InvalidConnackFlags(_)
+            | Error::InvalidWillQos
+            | Error::InvalidPublishQos(_)
+            | Error::InvalidQosFlags
+            | Error::InvalidWillRetain
+            | Error::MalformedPacket
+            | Error::ReservedPacketType
+            | Error::InvalidFixedHeaderFlags(_, _)
+            | Error::InvalidPropertyID(_)
+            | Error::InvalidWillPropertyID(_) => ReasonCode::MalformedPacket,
+            Error::PropertyAlreadyExists(_) | Error::ProtocolError(_) => ReasonCode::ProtocolError,
+            Error::InvalidTopic => ReasonCode::TopicNameInvalid,
+            Error::PacketTooLarge(_, _) => ReasonCode::PacketTooLarge,
+            Error::EmptySubscriptionTopic | Error::DuplicateTopicFilter(_) => {
+                ReasonCode::TopicFilterInvalid
+            }
+            Error::InvalidRemaningLength(_)
+            | Error::InvalidKeepAlive(_)
+            | Error::MaximumPacketSizeTooSmall(_, _) => ReasonCode::ImplSpecificError,
+        };
+        reason_code as u8
+    }
+}
+
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum PublishTopicValidationError {
     #[error("publish {}", Error::TopicLenTooLong)]
     TopicLenTooLong,
-    #[error("{} - publish topic cannot contain '+' or '*'", Error::InvalidTopic)]
-    InvalidTopic,
+    #[error(
+        "{} - publish topic '{topic}' cannot contain the character '{ch}'",
+        Error::InvalidTopic
+    )]
+    InvalidTopic { topic: String, ch: char },
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -45,8 +111,37 @@ pub enum SubscribeTopicValidationError {
     #[error("{}", Error::EmptySubscriptionTopic)]
     EmptySubscriptionTopic,
     #[error(
-        "{} - subscribe topic cannot contain the character '{0}'",
+        "{} - subscribe topic '{topic}' cannot contain the character '{ch}'",
         Error::InvalidTopic
     )]
-    InvalidTopic(char),
+    InvalidTopic { topic: String, ch: char },
+    #[error("invalid shared subscription '{0}'")]
+    InvalidSharedSubscription(String),
+    #[error("maximum number of subscriptions ({0}) reached")]
+    TooManySubscriptions(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    #[test]
+    fn test_reason_code() {
+        assert_eq!(
+            Error::IOError(mqttio::errors::Error::InvalidUTF8String).reason_code(),
+            0x81
+        );
+        assert_eq!(
+            Error::IOError(mqttio::errors::Error::PropertyAlreadyExists("Topic Alias"))
+                .reason_code(),
+            0x82
+        );
+        assert_eq!(Error::MalformedPacket.reason_code(), 0x81);
+        assert_eq!(Error::InvalidWillQos.reason_code(), 0x81);
+        assert_eq!(Error::InvalidPropertyID(0x7F).reason_code(), 0x81);
+        assert_eq!(Error::ProtocolError("test").reason_code(), 0x82);
+        assert_eq!(Error::InvalidProtocolVersion.reason_code(), 0x84);
+        assert_eq!(Error::InvalidTopic.reason_code(), 0x90);
+        assert_eq!(Error::EmptySubscriptionTopic.reason_code(), 0x8F);
+    }
 }
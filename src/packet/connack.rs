@@ -0,0 +1,267 @@
+use crate::errors::Error;
+use crate::propertyio_derive::IOOperations;
+
+use mqttio::io::{KeyValuePair, Reader, TeeReader, VarUint32Size, Writer};
+use mqttio::options::DecodeOptions;
+use mqttio::properties::{
+    Properties, PropertyID, PropertyReader, PropertySize, PropertyWriter, RawProperty,
+};
+use num::FromPrimitive;
+
+use super::packet::{write_remaining_length, PacketType};
+
+#[derive(Debug, Default, IOOperations)]
+pub struct ConnackProperties {
+    #[ioops(prop_id(PropertyID::SessionExpiryInterval))]
+    session_expiry_interval: Option<u32>,
+    #[ioops(prop_id(PropertyID::ReceiveMaximum))]
+    receive_maximum: Option<u16>,
+    #[ioops(prop_id(PropertyID::MaximumQoS))]
+    maximum_qos: Option<u8>,
+    #[ioops(prop_id(PropertyID::RetainAvailable))]
+    retain_available: Option<bool>,
+    #[ioops(prop_id(PropertyID::MaximumPacketSize))]
+    maximum_packet_size: Option<u32>,
+    #[ioops(prop_id(PropertyID::AssignedClientIdentifier))]
+    assigned_client_identifier: String,
+    #[ioops(prop_id(PropertyID::TopicAliasMaximum))]
+    topic_alias_maximum: Option<u16>,
+    #[ioops(prop_id(PropertyID::ReasonString))]
+    reason_string: String,
+    #[ioops(prop_id(PropertyID::UserProperty))]
+    user_property: Vec<KeyValuePair>,
+    #[ioops(prop_id(PropertyID::WildcardSubscriptionAvailable))]
+    wildcard_subscription_available: Option<bool>,
+    #[ioops(prop_id(PropertyID::SubscriptionIdentifierAvailable))]
+    subscription_identifier_available: Option<bool>,
+    #[ioops(prop_id(PropertyID::SharedSubscriptionAvailable))]
+    shared_subscription_available: Option<bool>,
+    #[ioops(prop_id(PropertyID::ServerKeepAlive))]
+    server_keep_alive: Option<u16>,
+    #[ioops(prop_id(PropertyID::ResponseInformation))]
+    response_information: String,
+    #[ioops(prop_id(PropertyID::ServerReference))]
+    server_reference: String,
+    #[ioops(prop_id(PropertyID::AuthenticationMethod))]
+    authentication_method: String,
+    #[ioops(prop_id(PropertyID::AuthenticationData), max_len = "max_auth_data")]
+    authentication_data: Vec<u8>,
+    #[ioops(unknown)]
+    unknown: Vec<RawProperty>,
+}
+
+#[derive(Debug, Default)]
+pub struct Connack {
+    session_present: bool,
+    reason_code: u8,
+    properties: Option<ConnackProperties>,
+}
+
+impl Connack {
+    // read reads the variable header of a CONNACK, the connect acknowledge
+    // flags, the reason code and the properties. Bits 7-1 of the flags are
+    // reserved and must be 0, MQTT 3.2.2.1
+    pub fn read<R: Reader>(r: &mut R) -> Result<Connack, Error> {
+        Connack::read_with_options(r, &DecodeOptions::default())
+    }
+
+    pub fn read_with_options<R: Reader>(
+        r: &mut R,
+        options: &DecodeOptions,
+    ) -> Result<Connack, Error> {
+        let flags = r.read_u8()?;
+        if flags & 0xFE != 0 {
+            return Err(Error::InvalidConnackFlags(flags));
+        }
+
+        Ok(Connack {
+            session_present: (flags & 0x01) > 0,
+            reason_code: r.read_u8()?,
+            properties: ConnackProperties::read_with_options(r, options)?,
+        })
+    }
+
+    fn property_length(&self) -> Result<u32, Error> {
+        self.properties
+            .as_ref()
+            .map_or(Ok(0), Properties::property_length)
+    }
+
+    fn remaining_length(&self) -> Result<u32, Error> {
+        let property_len = self.property_length()?;
+        // 2 = acknowledge flags + reason code
+        Ok(2 + VarUint32Size::size(property_len) + property_len)
+    }
+
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut packet = Vec::new();
+        self.write_to(&mut packet)?;
+        Ok(packet)
+    }
+
+    /// Encodes the packet into a writer.
+    pub fn write_to<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+        let remaining_len = self.remaining_length()?;
+
+        w.write_u8((PacketType::CONNACK as u8) << 0x04)?;
+        write_remaining_length(w, remaining_len)?;
+
+        w.write_u8(self.session_present as u8)?;
+        w.write_u8(self.reason_code)?;
+        w.write_property_block(self.properties.as_ref())?;
+        Ok(())
+    }
+}
+
+// negotiate_keep_alive returns the keep alive the client must use, the Server
+// Keep Alive of the CONNACK when the server sent one, otherwise the value the
+// client requested in CONNECT.
+// MQTT 3.2.2.3.14
+pub fn negotiate_keep_alive(requested: u16, connack: &Connack) -> u16 {
+    connack
+        .properties
+        .as_ref()
+        .and_then(|p| p.server_keep_alive)
+        .unwrap_or(requested)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use num::FromPrimitive;
+
+    use crate::errors::Error;
+    use crate::packet::packet::{FixedHeaderReader, PacketType};
+
+    use super::{negotiate_keep_alive, Connack, ConnackProperties};
+
+    #[test]
+    fn test_connack_packet() {
+        let data = [
+            0x20, 0x03, // fixed header
+            0x01, // session present
+            0x00, // success
+            0x00, // properties
+        ];
+
+        let mut cur = Cursor::new(data);
+        let (byte0, remaining_len) = FixedHeaderReader::read(&mut cur).unwrap();
+        assert_eq!(PacketType::from_u8(byte0 >> 4), Some(PacketType::CONNACK));
+        assert_eq!(remaining_len, 0x03);
+
+        let result = Connack::read(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let connack = result.unwrap();
+        assert!(connack.session_present);
+        assert_eq!(connack.reason_code, 0x00);
+        assert!(connack.properties.is_none());
+        assert_eq!(connack.write().unwrap().as_slice(), data);
+    }
+
+    #[test]
+    fn test_connack_packet_with_props() {
+        let data = [
+            0x20, 0x1A, // fixed header
+            0x00, // session present
+            0x00, // success
+            0x17, // properties
+            0x11, 0x00, 0x00, 0x0E, 0x10, // session expiry interval - 3600
+            0x21, 0x00, 0x0A, // receive maximum
+            0x24, 0x01, // maximum QoS
+            0x25, 0x00, // retain available
+            0x12, 0x00, 0x03, b'a', b'b', b'c', // assigned client identifier
+            0x2A, 0x00, // shared subscription available
+            0x13, 0x00, 0x3C, // server keep alive - 60
+        ];
+
+        let mut cur = Cursor::new(data);
+        let (_, remaining_len) = FixedHeaderReader::read(&mut cur).unwrap();
+        assert_eq!(remaining_len, 0x1A);
+
+        let result = Connack::read(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let connack = result.unwrap();
+        assert!(!connack.session_present);
+        let props = connack.properties.as_ref().unwrap();
+        assert_eq!(props.session_expiry_interval, Some(3600));
+        assert_eq!(props.receive_maximum, Some(10));
+        assert_eq!(props.maximum_qos, Some(1));
+        assert_eq!(props.retain_available, Some(false));
+        assert_eq!(props.assigned_client_identifier, "abc");
+        assert_eq!(props.server_keep_alive, Some(60));
+        assert_eq!(props.shared_subscription_available, Some(false));
+
+        let written_result = connack.write();
+        assert!(
+            written_result.is_ok(),
+            "Error writing CONNACK packet with properties {}",
+            written_result.unwrap_err()
+        );
+        assert_eq!(written_result.unwrap().as_slice(), data);
+    }
+
+    #[test]
+    fn test_connack_round_trip() {
+        let connack = Connack {
+            session_present: true,
+            reason_code: 0x00,
+            properties: Some(ConnackProperties {
+                maximum_packet_size: Some(1024),
+                topic_alias_maximum: Some(5),
+                reason_string: "ok".to_string(),
+                user_property: vec![("k".to_string(), "v".to_string())],
+                wildcard_subscription_available: Some(true),
+                subscription_identifier_available: Some(false),
+                response_information: "info".to_string(),
+                server_reference: "other".to_string(),
+                authentication_method: "SCRAM-SHA-1".to_string(),
+                authentication_data: vec![0x01, 0x02],
+                ..Default::default()
+            }),
+        };
+        let encoded = connack.write().unwrap();
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        assert!(FixedHeaderReader::read(&mut cur).is_ok());
+        let decoded = Connack::read(&mut cur).unwrap();
+        assert_eq!(cur.position() as usize, encoded.len());
+        assert!(decoded.session_present);
+        let props = decoded.properties.as_ref().unwrap();
+        assert_eq!(props.maximum_packet_size, Some(1024));
+        assert_eq!(props.authentication_data, vec![0x01, 0x02]);
+        assert_eq!(decoded.write().unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_negotiate_keep_alive() {
+        let mut connack = Connack::default();
+        assert_eq!(negotiate_keep_alive(60, &connack), 60);
+
+        connack.properties = Some(ConnackProperties {
+            receive_maximum: Some(10),
+            ..Default::default()
+        });
+        assert_eq!(negotiate_keep_alive(60, &connack), 60);
+
+        // the server value wins, even when it disables the keep alive
+        for server_keep_alive in [30, 120, 0] {
+            connack.properties = Some(ConnackProperties {
+                server_keep_alive: Some(server_keep_alive),
+                ..Default::default()
+            });
+            assert_eq!(negotiate_keep_alive(60, &connack), server_keep_alive);
+            assert_eq!(negotiate_keep_alive(0, &connack), server_keep_alive);
+        }
+    }
+
+    #[test]
+    fn test_connack_reserved_flags() {
+        for flags in [0x02, 0x80, 0xFF] {
+            let mut cur = Cursor::new([flags, 0x00, 0x00]);
+            let result = Connack::read(&mut cur);
+            assert_eq!(result.unwrap_err(), Error::InvalidConnackFlags(flags));
+        }
+        assert_eq!(Error::InvalidConnackFlags(0x02).reason_code(), 0x81);
+    }
+}
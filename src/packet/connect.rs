@@ -1,13 +1,14 @@
-use std::io::Cursor;
-
 use crate::errors::Error;
 use crate::propertyio_derive::IOOperations;
 
-use mqttio::io::{BinaryData, KeyValuePair, Reader, UTF8String, VarUint32Size, Writer};
-use mqttio::properties::{PropertyID, PropertyReader, PropertySize, PropertyWriter};
+use mqttio::io::{BinaryData, KeyValuePair, Reader, TeeReader, UTF8String, VarUint32Size, Writer};
+use mqttio::options::DecodeOptions;
+use mqttio::properties::{
+    Properties, PropertyID, PropertyReader, PropertySize, PropertyWriter, RawProperty,
+};
 use num::FromPrimitive;
 
-use super::packet::PacketType;
+use super::packet::{write_remaining_length, PacketType};
 
 #[derive(Debug, Default, IOOperations)]
 pub struct WillProperties {
@@ -25,6 +26,8 @@ pub struct WillProperties {
     correlation_data: Vec<u8>,
     #[ioops(prop_id(PropertyID::UserProperty))]
     user_property: Vec<KeyValuePair>,
+    #[ioops(unknown)]
+    unknown: Vec<RawProperty>,
 }
 
 #[derive(Debug, Default)]
@@ -38,18 +41,44 @@ pub struct Will {
 
 impl Will {
     pub fn read<R: Reader>(r: &mut R, flag: u8) -> Result<Will, Error> {
+        Will::read_with_options(r, flag, &DecodeOptions::default())
+    }
+
+    pub fn read_with_options<R: Reader>(
+        r: &mut R,
+        flag: u8,
+        options: &DecodeOptions,
+    ) -> Result<Will, Error> {
         let mut will: Will = Default::default();
         will.qos = 0x03 & (flag >> 0x03);
+        // checked here as well so that the will can be read without the
+        // connect flag validation
+        if will.qos > 2 {
+            return Err(Error::InvalidWillQos);
+        }
         will.retain = (flag & 0x20) > 0;
 
         // Will properties
-        will.properties = WillProperties::read(r)?;
+        will.properties = WillProperties::read_with_options(r, options)?;
 
         will.topic = r.read_utf8_string()?;
         will.payload = r.read_binary()?;
 
         return Ok(will);
     }
+
+    // validate checks the will before its flags are written. A retained will
+    // message needs a topic to be published to, and the QoS must fit the two
+    // flag bits.
+    fn validate(&self) -> Result<(), Error> {
+        if self.qos > 2 {
+            return Err(Error::InvalidWillQos);
+        }
+        if self.retain && self.topic.is_empty() {
+            return Err(Error::InvalidWillRetain);
+        }
+        Ok(())
+    }
 }
 
 fn validate_connect_flag(flag: u8) -> Result<(), Error> {
@@ -91,8 +120,108 @@ pub struct ConnectProperties {
     user_property: Vec<KeyValuePair>,
     #[ioops(prop_id(PropertyID::AuthenticationMethod))]
     authentication_method: String,
-    #[ioops(prop_id(PropertyID::AuthenticationData))]
+    #[ioops(prop_id(PropertyID::AuthenticationData), max_len = "max_auth_data")]
     authentication_data: Vec<u8>,
+    #[ioops(unknown)]
+    unknown: Vec<RawProperty>,
+}
+
+impl ConnectProperties {
+    // validate checks the rules that span more than one property. It is a
+    // protocol error to include authentication data without an
+    // authentication method, MQTT 3.1.2.11.10
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.authentication_data.is_empty() && self.authentication_method.is_empty() {
+            return Err(Error::ProtocolError(
+                "authentication data without authentication method",
+            ));
+        }
+        Ok(())
+    }
+
+    // allows_problem_info reports whether the server may send a Reason String
+    // or User Properties on packets other than PUBLISH, CONNACK and
+    // DISCONNECT. Request Problem Information defaults to 1 when absent.
+    pub fn allows_problem_info(&self) -> bool {
+        self.request_problem_info.unwrap_or(true)
+    }
+
+    // validate_maximum_packet_size is an opt-in check that the declared
+    // maximum packet size leaves room for at least the smallest packets the
+    // peer needs to send, MIN_MAXIMUM_PACKET_SIZE is a sensible floor.
+    pub fn validate_maximum_packet_size(&self, floor: u32) -> Result<(), Error> {
+        match self.maximum_packet_size {
+            Some(size) if size < floor => Err(Error::MaximumPacketSizeTooSmall(size, floor)),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub const MIN_MAXIMUM_PACKET_SIZE: u32 = 20;
+
+// AuthExchange follows an enhanced authentication exchange, CONNECT followed
+// by AUTH packets until the CONNACK. The Authentication Method of every AUTH
+// must be the one of the CONNECT, MQTT 4.12, the latest Authentication Data is
+// kept.
+#[derive(Debug)]
+pub struct AuthExchange {
+    method: String,
+    data: Vec<u8>,
+}
+
+impl AuthExchange {
+    // start begins the exchange from the CONNECT properties, None when the
+    // CONNECT does not request enhanced authentication.
+    pub fn start(props: &ConnectProperties) -> Option<AuthExchange> {
+        if props.authentication_method.is_empty() {
+            return None;
+        }
+        Some(AuthExchange {
+            method: props.authentication_method.clone(),
+            data: props.authentication_data.clone(),
+        })
+    }
+
+    // continue_with records the Authentication Method and Data of an AUTH, a
+    // different method is a protocol error.
+    pub fn continue_with(&mut self, method: &str, data: &[u8]) -> Result<(), Error> {
+        if method != self.method {
+            return Err(Error::ProtocolError(
+                "authentication method changed during the exchange",
+            ));
+        }
+        self.data = data.to_vec();
+        Ok(())
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+pub struct KeepAlive {}
+
+impl KeepAlive {
+    // from_secs converts a keep alive interval into the two byte field of
+    // CONNECT, 0 disables the keep alive mechanism.
+    pub fn from_secs(secs: u64) -> Result<u16, Error> {
+        u16::try_from(secs).map_err(|_| Error::InvalidKeepAlive(secs))
+    }
+}
+
+// SessionParams holds the parameters of a CONNECT a broker needs to set up
+// the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionParams {
+    pub client_id: String,
+    pub clean_start: bool,
+    pub session_expiry_interval: u32,
+    pub keep_alive: u16,
+    pub receive_maximum: u16,
 }
 
 #[derive(Debug, Default)]
@@ -108,27 +237,51 @@ pub struct Connect {
     password: Vec<u8>,
 }
 
+// read_protocol_header reads and validates the protocol name, the protocol
+// version and the connect flags, returning the connect flags
+fn read_protocol_header<R: Reader>(r: &mut R) -> Result<u8, Error> {
+    let pname_len = r.read_u16()?;
+    if pname_len != 4 {
+        return Err(Error::InvalidProtocolNameLength(pname_len));
+    }
+
+    let pname = Reader::read_array::<4>(r)?;
+    if pname != [b'M', b'Q', b'T', b'T'] {
+        let v = match std::str::from_utf8(&pname) {
+            Ok(v) => v,
+            Err(_e) => "malformed content",
+        };
+        return Err(Error::InvalidProtocolName(v.to_string()));
+    }
+
+    let protocol_version = r.read_u8()?;
+    if protocol_version != 0x05 {
+        return Err(Error::InvalidProtocolVersion);
+    }
+
+    let connect_flag = r.read_u8()?;
+
+    validate_connect_flag(connect_flag)?;
+    Ok(connect_flag)
+}
+
 impl Connect {
     pub fn read<R: Reader>(r: &mut R) -> Result<Connect, Error> {
-        let pname = Reader::read_exact::<6>(r)?;
-        if pname != [0, 4, b'M', b'Q', b'T', b'T'] {
-            let v = match std::str::from_utf8(&pname) {
-                Ok(v) => v,
-                Err(_e) => "malformed content",
-            };
-            return Err(Error::InvalidProtocolName(v.to_string()));
-        }
-        let mut connect: Connect = Default::default();
-        connect.protocol_name = "MQTT";
-
-        connect.protocol_version = r.read_u8()?;
-        if connect.protocol_version != 0x05 {
-            return Err(Error::InvalidProtocolVersion);
-        }
+        Connect::read_with_options(r, &DecodeOptions::default())
+    }
 
-        let connect_flag = r.read_u8()?;
+    // read_with_options reads a CONNECT with the limits of the decode
+    // options applied to its property blocks, e.g. the size of the
+    // authentication data.
+    pub fn read_with_options<R: Reader>(
+        r: &mut R,
+        options: &DecodeOptions,
+    ) -> Result<Connect, Error> {
+        let connect_flag = read_protocol_header(r)?;
 
-        validate_connect_flag(connect_flag)?;
+        let mut connect: Connect = Default::default();
+        connect.protocol_name = "MQTT";
+        connect.protocol_version = 0x05;
 
         connect.clean_start = (connect_flag & 0x02) > 0;
         let will_flag = (connect_flag & 0x04) > 0;
@@ -138,13 +291,16 @@ impl Connect {
 
         connect.keep_alive = r.read_u16()?;
 
-        connect.properties = ConnectProperties::read(r)?;
+        connect.properties = ConnectProperties::read_with_options(r, options)?;
+        if let Some(properties) = &connect.properties {
+            properties.validate()?;
+        }
 
         connect.client_id = r.read_utf8_string()?;
 
         if will_flag {
             println!("has will packet");
-            let will = Will::read(r, connect_flag)?;
+            let will = Will::read_with_options(r, connect_flag, options)?;
             connect.will = Some(will);
         }
 
@@ -159,39 +315,114 @@ impl Connect {
         return Ok(connect);
     }
 
-    fn will_property_length(&self) -> u32 {
-        if self.will.is_some() && self.will.as_ref().unwrap().properties.is_some() {
-            return self
-                .will
-                .as_ref()
-                .unwrap()
-                .properties
-                .as_ref()
-                .unwrap()
-                .len();
-        }
-        0
+    /// Reads the client identifier and the clean start flag of a CONNECT
+    /// without decoding the will, the user name and the password. The
+    /// header is validated as in `read` and the properties are decoded and
+    /// discarded. The stream is consumed up to and including the client id.
+    pub fn peek_identity<R: Reader>(r: &mut R) -> Result<(String, bool), Error> {
+        let connect_flag = read_protocol_header(r)?;
+        let clean_start = (connect_flag & 0x02) > 0;
+
+        r.read_u16()?; // keep alive
+        ConnectProperties::read(r)?;
+
+        let client_id = r.read_utf8_string()?;
+        Ok((client_id, clean_start))
     }
 
-    fn property_length(&self) -> u32 {
-        if self.properties.is_some() {
-            return self.properties.as_ref().unwrap().len();
+    // session_params gathers the session parameters of the CONNECT. An absent
+    // Session Expiry Interval is 0, the session ends with the network
+    // connection, and an absent Receive Maximum is 65535.
+    // MQTT 3.1.2.11.2, 3.1.2.11.3
+    pub fn session_params(&self) -> SessionParams {
+        let props = self.properties.as_ref();
+        SessionParams {
+            client_id: self.client_id.clone(),
+            clean_start: self.clean_start,
+            session_expiry_interval: props.and_then(|p| p.session_expiry_interval).unwrap_or(0),
+            keep_alive: self.keep_alive,
+            receive_maximum: props.and_then(|p| p.receive_maximum).unwrap_or(65535),
         }
-        0
     }
 
-    pub fn write(&self) -> Result<Vec<u8>, Error> {
-        let property_len = self.property_length();
+    fn will_property_length(&self) -> Result<u32, Error> {
+        self.will
+            .as_ref()
+            .and_then(|will| will.properties.as_ref())
+            .map_or(Ok(0), Properties::property_length)
+    }
 
-        let will_property_len = self.will_property_length();
+    fn property_length(&self) -> Result<u32, Error> {
+        self.properties
+            .as_ref()
+            .map_or(Ok(0), Properties::property_length)
+    }
+
+    fn remaining_length(&self) -> Result<u32, Error> {
+        let property_len = self.property_length()?;
 
-        // calculate the remaining length
         // 10 = protocolname + version + flags + keepalive
         let mut remaining_len = 10
             + property_len
             + VarUint32Size::size(property_len)
             + UTF8String::size(&self.client_id);
 
+        if let Some(will) = &self.will {
+            let will_property_len = self.will_property_length()?;
+            remaining_len += will_property_len + VarUint32Size::size(will_property_len);
+            remaining_len += UTF8String::size(&will.topic) + BinaryData::size(&will.payload);
+        }
+
+        if !self.user_name.is_empty() {
+            remaining_len += UTF8String::size(&self.user_name);
+        }
+
+        if !self.password.is_empty() {
+            remaining_len += BinaryData::size(&self.password);
+        }
+        Ok(remaining_len)
+    }
+
+    /// Returns the number of bytes `write` produces for the packet, the
+    /// fixed header included. Fails when a property block is too large to
+    /// be encoded.
+    pub fn encoded_size(&self) -> Result<u32, Error> {
+        let remaining_len = self.remaining_length()?;
+        Ok(1 + VarUint32Size::size(remaining_len) + remaining_len)
+    }
+
+    /// Reports whether the encoded packet is within a maximum packet size,
+    /// e.g. the MaximumPacketSize of the server.
+    pub fn fits_within(&self, max: u32) -> bool {
+        self.encoded_size().is_ok_and(|size| size <= max)
+    }
+
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut packet = Vec::new();
+        self.write_into_vec(&mut packet)?;
+        Ok(packet)
+    }
+
+    /// Appends the encoded packet to `buf`, e.g. to batch several packets
+    /// into one write. The buffer is left as it was when encoding fails.
+    pub fn write_into_vec(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let encoded_size =
+            usize::try_from(self.encoded_size()?).map_err(Error::InvalidRemaningLength)?;
+        buf.reserve(encoded_size);
+
+        let len = buf.len();
+        let result = self.write_to(buf);
+        if result.is_err() {
+            buf.truncate(len);
+        }
+        result
+    }
+
+    /// Encodes the packet into a writer.
+    pub fn write_to<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+        // calculate the remaining length
+        let remaining_len = self.remaining_length()?;
+
         let mut connect_flags: u8 = 0;
         if self.clean_start {
             connect_flags |= 0x02;
@@ -200,68 +431,49 @@ impl Connect {
         if self.will.is_some() {
             connect_flags |= 0x04; // Will flag
             let will = self.will.as_ref().unwrap();
+            will.validate()?;
             connect_flags |= will.qos << 0x03;
             if will.retain {
                 connect_flags |= 0x20;
             }
-            remaining_len += will_property_len + VarUint32Size::size(will_property_len);
-            remaining_len += UTF8String::size(&will.topic) + BinaryData::size(&will.payload);
         }
 
         if self.user_name.len() > 0 {
             connect_flags |= 0x80;
-            remaining_len += UTF8String::size(&self.user_name);
         }
 
         if self.password.len() > 0 {
             connect_flags |= 0x40;
-            remaining_len += BinaryData::size(&self.password);
         }
 
-        let remaining_len_usize = usize::try_from(remaining_len);
-        if remaining_len_usize.is_err() {
-            return Err(Error::InvalidRemaningLength(
-                remaining_len_usize.unwrap_err(),
-            ));
-        }
-        let mut packet = Cursor::new(Vec::<u8>::with_capacity(remaining_len_usize.unwrap()));
-        packet.write_u8((PacketType::CONNECT as u8) << 0x04)?;
-        packet.write_varuint32(remaining_len)?;
+        w.write_u8((PacketType::CONNECT as u8) << 0x04)?;
+        write_remaining_length(w, remaining_len)?;
 
-        packet.write_utf8_string("MQTT")?;
-        packet.write_u8(0x05)?; // version
+        w.write_utf8_string("MQTT")?;
+        w.write_u8(0x05)?; // version
 
-        packet.write_u8(connect_flags)?;
+        w.write_u8(connect_flags)?;
 
-        packet.write_u16(self.keep_alive)?;
+        w.write_u16(self.keep_alive)?;
 
-        packet.write_varuint32(property_len)?;
+        w.write_property_block(self.properties.as_ref())?;
 
-        if self.properties.is_some() {
-            self.properties.as_ref().unwrap().write(&mut packet)?;
-        }
+        w.write_utf8_string(&self.client_id)?;
 
-        packet.write_utf8_string(&self.client_id)?;
-
-        if self.will.is_some() {
-            let will = self.will.as_ref().unwrap();
-            packet.write_varuint32(will_property_len)?;
-            if will.properties.is_some() {
-                let will_props = will.properties.as_ref().unwrap();
-                will_props.write(&mut packet)?;
-            }
-            packet.write_utf8_string(&will.topic)?;
-            packet.write_binary(&will.payload)?;
+        if let Some(will) = &self.will {
+            w.write_property_block(will.properties.as_ref())?;
+            w.write_utf8_string(&will.topic)?;
+            w.write_binary(&will.payload)?;
         }
 
         if self.user_name.len() > 0 {
-            packet.write_utf8_string(&self.user_name)?;
+            w.write_utf8_string(&self.user_name)?;
         }
 
         if self.password.len() > 0 {
-            packet.write_binary(&self.password)?;
+            w.write_binary(&self.password)?;
         }
-        return Ok(packet.into_inner());
+        return Ok(());
     }
 }
 
@@ -276,7 +488,503 @@ mod tests {
         packet::packet::{FixedHeaderReader, PacketType},
     };
 
-    use super::Connect;
+    use mqttio::io::{CountingWriter, Writer};
+    use mqttio::options::DecodeOptions;
+    use mqttio::properties::{Properties, PropertySize};
+
+    use super::{
+        AuthExchange, Connect, ConnectProperties, KeepAlive, SessionParams, Will, WillProperties,
+        MIN_MAXIMUM_PACKET_SIZE,
+    };
+
+    fn write_property_block<P: Properties<Error = Error>>(props: &P) -> Vec<u8> {
+        let mut cur = Cursor::new(Vec::new());
+        let result = props.write_block(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        cur.into_inner()
+    }
+
+    // round_trip writes the property block of any Properties implementor, reads
+    // it back and checks that the decoded value encodes to the same bytes
+    fn round_trip<P: Properties<Error = Error>>(props: &P) {
+        let encoded = write_property_block(props);
+        let mut cur = Cursor::new(encoded.as_slice());
+        let decoded = match P::read(&mut cur) {
+            Ok(v) => v,
+            Err(e) => panic!("Error reading the property block {}", e),
+        };
+        assert!(decoded.is_some());
+        assert_eq!(cur.position() as usize, encoded.len());
+        assert_eq!(write_property_block(&decoded.unwrap()), encoded);
+    }
+
+    // property_length must be the exact number of bytes write emits, the
+    // property length prefix not included
+    fn assert_length_matches_write<P: Properties<Error = Error>>(props: &P) {
+        let mut cur = Cursor::new(Vec::new());
+        let result = props.write(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(
+            props.property_length().unwrap() as usize,
+            cur.get_ref().len()
+        );
+    }
+
+    #[test]
+    fn test_property_length_matches_write() {
+        assert_length_matches_write(&ConnectProperties::default());
+        assert_length_matches_write(&ConnectProperties {
+            session_expiry_interval: Some(3600),
+            receive_maximum: Some(10),
+            maximum_packet_size: Some(1024),
+            topic_alias_maximum: Some(5),
+            request_problem_info: Some(false),
+            request_response_info: Some(true),
+            user_property: vec![
+                ("a".to_string(), "b".to_string()),
+                ("key".to_string(), "value".to_string()),
+            ],
+            authentication_method: "SCRAM-SHA-1".to_string(),
+            authentication_data: vec![0x01, 0x02, 0x03],
+            unknown: vec![(0x23, vec![0x00, 0x05])],
+        });
+
+        assert_length_matches_write(&WillProperties::default());
+        assert_length_matches_write(&WillProperties {
+            will_delay_interval: Some(1024),
+            payload_format_indicator: Some(true),
+            message_expiry_interval: Some(60),
+            content_type: "text/plain".to_string(),
+            response_topic: "a/b".to_string(),
+            correlation_data: vec![0x0A, 0x0B],
+            user_property: vec![("k".to_string(), "".to_string())],
+            unknown: vec![(0x23, vec![0x00, 0x05])],
+        });
+    }
+
+    #[test]
+    fn test_write_property_block() {
+        let props = ConnectProperties {
+            receive_maximum: Some(10),
+            user_property: vec![("a".to_string(), "b".to_string())],
+            ..Default::default()
+        };
+        let mut cur = Cursor::new(Vec::new());
+        let result = cur.write_property_block(Some(&props));
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(cur.into_inner(), write_property_block(&props));
+
+        // an absent and an empty block are written as a zero length
+        let mut cur = Cursor::new(Vec::new());
+        assert!(cur.write_property_block::<WillProperties>(None).is_ok());
+        assert!(cur
+            .write_property_block(Some(&WillProperties::default()))
+            .is_ok());
+        assert_eq!(cur.into_inner(), [0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_auth_exchange() {
+        assert!(AuthExchange::start(&ConnectProperties::default()).is_none());
+
+        let props = ConnectProperties {
+            authentication_method: "SCRAM-SHA-1".to_string(),
+            authentication_data: vec![0x01],
+            ..Default::default()
+        };
+        let mut exchange = AuthExchange::start(&props).unwrap();
+        assert_eq!(exchange.method(), "SCRAM-SHA-1");
+        assert_eq!(exchange.data(), [0x01]);
+
+        assert!(exchange.continue_with("SCRAM-SHA-1", &[0x02, 0x03]).is_ok());
+        assert!(exchange.continue_with("SCRAM-SHA-1", &[0x04]).is_ok());
+        assert_eq!(exchange.data(), [0x04]);
+
+        // a second AUTH changing the method
+        let result = exchange.continue_with("GS2-KRB5", &[0x05]);
+        assert!(matches!(result, Err(Error::ProtocolError(_))));
+        assert_eq!(result.unwrap_err().reason_code(), 0x82);
+        assert_eq!(exchange.data(), [0x04]);
+    }
+
+    #[test]
+    fn test_session_params() {
+        let mut connect = Connect {
+            protocol_name: "MQTT",
+            protocol_version: 5,
+            clean_start: true,
+            keep_alive: 30,
+            client_id: "client".to_string(),
+            ..Default::default()
+        };
+        let defaults = SessionParams {
+            client_id: "client".to_string(),
+            clean_start: true,
+            session_expiry_interval: 0,
+            keep_alive: 30,
+            receive_maximum: 65535,
+        };
+        assert_eq!(connect.session_params(), defaults);
+
+        // a property block without the session properties
+        connect.properties = Some(ConnectProperties {
+            maximum_packet_size: Some(1024),
+            ..Default::default()
+        });
+        assert_eq!(connect.session_params(), defaults);
+
+        connect.properties = Some(ConnectProperties {
+            session_expiry_interval: Some(3600),
+            receive_maximum: Some(10),
+            ..Default::default()
+        });
+        let params = connect.session_params();
+        assert_eq!(params.session_expiry_interval, 3600);
+        assert_eq!(params.receive_maximum, 10);
+        assert_eq!(params.keep_alive, 30);
+    }
+
+    #[test]
+    fn test_write_block() {
+        let props = WillProperties {
+            will_delay_interval: Some(5),
+            content_type: "text/plain".to_string(),
+            ..Default::default()
+        };
+        let mut cur = Cursor::new(Vec::new());
+        assert!(props.write_block(&mut cur).is_ok());
+        let encoded = cur.into_inner();
+        // the length prefix covers exactly the properties that follow it
+        assert_eq!(encoded[0] as usize, encoded.len() - 1);
+        assert_eq!(encoded[0] as u32, props.len().unwrap());
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        let decoded = WillProperties::read(&mut cur).unwrap().unwrap();
+        assert_eq!(cur.position() as usize, encoded.len());
+        assert_eq!(decoded.will_delay_interval, Some(5));
+        assert_eq!(decoded.content_type, "text/plain");
+    }
+
+    #[test]
+    fn test_properties_round_trip() {
+        round_trip(&ConnectProperties {
+            session_expiry_interval: Some(3600),
+            receive_maximum: Some(10),
+            maximum_packet_size: Some(1024),
+            topic_alias_maximum: Some(5),
+            request_problem_info: Some(false),
+            request_response_info: Some(true),
+            authentication_method: "SCRAM-SHA-1".to_string(),
+            authentication_data: vec![0x01, 0x02, 0x03],
+            ..Default::default()
+        });
+
+        round_trip(&WillProperties {
+            will_delay_interval: Some(1024),
+            payload_format_indicator: Some(true),
+            message_expiry_interval: Some(60),
+            content_type: "text/plain".to_string(),
+            response_topic: "a/b".to_string(),
+            correlation_data: vec![0x0A, 0x0B],
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_properties_user_property_round_trip() {
+        let props = ConnectProperties {
+            receive_maximum: Some(10),
+            user_property: vec![
+                ("region".to_string(), "eu".to_string()),
+                ("client".to_string(), "sensor-1".to_string()),
+                ("empty".to_string(), "".to_string()),
+            ],
+            ..Default::default()
+        };
+        let encoded = write_property_block(&props);
+        // property length + receive maximum + one id per user property
+        assert_eq!(props.len(), Ok(3 + (5 + 8) + (5 + 14) + (5 + 5)));
+        assert_eq!(encoded.len() as u32, 1 + props.len().unwrap());
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        let result = ConnectProperties::read(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let decoded = result.unwrap().unwrap();
+        assert_eq!(cur.position() as usize, encoded.len());
+        assert_eq!(decoded.receive_maximum, Some(10));
+        assert_eq!(decoded.user_property, props.user_property);
+
+        round_trip(&props);
+    }
+
+    #[test]
+    fn test_properties_unknown_round_trip() {
+        let data = [
+            0x0B, // properties
+            0x21, 0x00, 0x0A, // receive maximum
+            0x23, 0x00, 0x05, // topic alias, not a CONNECT property
+            0x27, 0x00, 0x00, 0x04, 0x00, // maximum packet size
+        ];
+
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read(&mut cur);
+        assert_eq!(result.unwrap_err(), Error::InvalidPropertyID(0x23));
+
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_allow_unknown(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let props = result.unwrap().unwrap();
+        assert_eq!(props.receive_maximum, Some(10));
+        assert_eq!(props.maximum_packet_size, Some(1024));
+        assert_eq!(props.unknown, vec![(0x23, vec![0x00, 0x05])]);
+
+        // the unknown property is written after the known ones
+        assert_eq!(
+            write_property_block(&props),
+            [0x0B, 0x21, 0x00, 0x0A, 0x27, 0x00, 0x00, 0x04, 0x00, 0x23, 0x00, 0x05]
+        );
+    }
+
+    #[test]
+    fn test_properties_read_with_raw() {
+        let data = [
+            0x0E, // properties
+            0x21, 0x00, 0x0A, // receive maximum
+            0x27, 0x00, 0x00, 0x04, 0x00, // maximum packet size
+            0x26, 0x00, 0x01, b'a', 0x00, 0x00, // user property
+            0x00, 0x01, // client id, not part of the block
+        ];
+
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_raw(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let (props, raw) = result.unwrap();
+        let props = props.unwrap();
+        assert_eq!(raw, data[..15]);
+        assert_eq!(cur.position(), 15);
+        assert_eq!(props.receive_maximum, Some(10));
+        assert_eq!(props.maximum_packet_size, Some(1024));
+        assert_eq!(props.user_property, vec![("a".to_string(), "".to_string())]);
+
+        // an empty block is captured as its length only
+        let mut cur = Cursor::new([0x00]);
+        let (props, raw) = WillProperties::read_with_raw(&mut cur).unwrap();
+        assert!(props.is_none());
+        assert_eq!(raw, [0x00]);
+    }
+
+    #[test]
+    fn test_property_length_too_large() {
+        // the largest property length a variable byte integer can hold
+        let max = 268435455;
+        let expected = mqttio::errors::Error::PropertyLengthTooLarge;
+        assert_eq!(PropertySize::add(max - 4, 4), Ok(max));
+        assert_eq!(PropertySize::add(max - 3, 4), Err(expected.clone()));
+
+        // accumulated like the generated len, the sizes of full authentication
+        // data properties stop at the limit instead of wrapping
+        let size = PropertySize::of_binary_data_with_id(&[0; 65535]);
+        let mut property_len = 0;
+        let result = loop {
+            match PropertySize::add(property_len, size) {
+                Ok(v) => property_len = v,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(result, expected);
+        assert!(property_len <= max && property_len + size > max);
+    }
+
+    #[test]
+    fn test_properties_max_user_properties() {
+        let data = [
+            0x0F, // properties
+            0x26, 0x00, 0x01, b'a', 0x00, 0x00, // user property
+            0x26, 0x00, 0x01, b'b', 0x00, 0x00, // user property
+            0x21, 0x00, 0x0A, // receive maximum
+        ];
+
+        let mut options = DecodeOptions {
+            max_user_properties: 2,
+            ..Default::default()
+        };
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_options(&mut cur, &options);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let props = result.unwrap().unwrap();
+        assert_eq!(props.user_property.len(), 2);
+        assert_eq!(props.receive_maximum, Some(10));
+
+        options.max_user_properties = 1;
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_options(&mut cur, &options);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+
+        // no limit by default
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_options(&mut cur, &DecodeOptions::default());
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+    }
+
+    #[test]
+    fn test_properties_max_auth_data() {
+        let data = [
+            0x0C, // properties
+            0x15, 0x00, 0x01, b'm', // authentication method
+            0x16, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, // authentication data
+        ];
+
+        let mut options = DecodeOptions {
+            max_auth_data: 5,
+            ..Default::default()
+        };
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_options(&mut cur, &options);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let props = result.unwrap().unwrap();
+        assert_eq!(props.authentication_data, [0x01, 0x02, 0x03, 0x04, 0x05]);
+
+        options.max_auth_data = 4;
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_options(&mut cur, &options);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+
+        // the declared length is rejected before the data is read
+        let mut cur = Cursor::new([0x04, 0x16, 0xFF, 0xFF, 0x01]);
+        let result = ConnectProperties::read_with_options(&mut cur, &options);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        assert_eq!(cur.position(), 4);
+    }
+
+    #[test]
+    fn test_connect_read_with_options() {
+        let connect = Connect {
+            client_id: "c".to_string(),
+            properties: Some(ConnectProperties {
+                authentication_method: "m".to_string(),
+                authentication_data: vec![0x01, 0x02, 0x03, 0x04, 0x05],
+                user_property: vec![("k".to_string(), "v".to_string())],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let encoded = connect.write().unwrap();
+
+        let mut options = DecodeOptions {
+            max_auth_data: 5,
+            max_user_properties: 1,
+            ..Default::default()
+        };
+        let mut cur = Cursor::new(encoded.as_slice());
+        assert!(FixedHeaderReader::read(&mut cur).is_ok());
+        let result = Connect::read_with_options(&mut cur, &options);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+
+        // both limits are applied to the properties of the packet
+        for (max_auth_data, max_user_properties) in [(4, 1), (5, 0)] {
+            options.max_auth_data = max_auth_data;
+            options.max_user_properties = max_user_properties;
+            let mut cur = Cursor::new(encoded.as_slice());
+            assert!(FixedHeaderReader::read(&mut cur).is_ok());
+            let result = Connect::read_with_options(&mut cur, &options);
+            assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        }
+    }
+
+    #[test]
+    fn test_properties_min_size() {
+        // receive maximum declared in a one byte block, the value follows
+        // the block
+        let data = [0x01, 0x21, 0x00, 0x0A];
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read(&mut cur);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        assert_eq!(cur.position(), 2);
+
+        // without the check the value is read past the block
+        let options = DecodeOptions {
+            check_property_min_size: false,
+            ..Default::default()
+        };
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read_with_options(&mut cur, &options);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        assert_eq!(cur.position(), 4);
+
+        // a property id at the very end of a block
+        for data in [
+            vec![0x01, 0x26],
+            vec![0x03, 0x01, 0x01, 0x03],
+            vec![0x06, 0x01, 0x01, 0x03, 0x00, 0x00, 0x18],
+        ] {
+            let mut cur = Cursor::new(data.as_slice());
+            let result = WillProperties::read(&mut cur);
+            assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+            assert_eq!(cur.position() as usize, data.len());
+        }
+
+        // the smallest values fit
+        let mut cur = Cursor::new([0x05, 0x26, 0x00, 0x00, 0x00, 0x00]);
+        let props = WillProperties::read(&mut cur).unwrap().unwrap();
+        assert_eq!(props.user_property, vec![("".to_string(), "".to_string())]);
+
+        // an empty content type still uses 3 bytes of the block, the bytes
+        // after the block are not read
+        let data = [0x03, 0x03, 0x00, 0x00, 0x00, 0x01, b'x'];
+        let mut cur = Cursor::new(data);
+        let props = WillProperties::read(&mut cur).unwrap().unwrap();
+        assert!(props.content_type.is_empty());
+        assert_eq!(cur.position(), 4);
+    }
+
+    #[test]
+    fn test_authentication_data_without_method() {
+        let props = ConnectProperties {
+            authentication_data: vec![0x01, 0x02],
+            ..Default::default()
+        };
+        assert!(matches!(props.validate(), Err(Error::ProtocolError(_))));
+
+        let props = ConnectProperties {
+            authentication_method: "SCRAM-SHA-1".to_string(),
+            authentication_data: vec![0x01, 0x02],
+            ..Default::default()
+        };
+        assert!(props.validate().is_ok());
+        assert!(ConnectProperties::default().validate().is_ok());
+
+        // CONNECT carrying authentication data only
+        let mut cur = Cursor::new([
+            0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3C, // header
+            0x04, 0x16, 0x00, 0x01, 0x01, // authentication data
+            0x00, 0x00, // client id
+        ]);
+        let result = Connect::read(&mut cur);
+        assert!(matches!(result, Err(Error::ProtocolError(_))));
+    }
+
+    #[test]
+    fn test_property_overshoots_block() {
+        // the authentication method length prefix claims 8 bytes, the block
+        // ends after 4 of them
+        let data = [
+            0x07, // properties
+            0x15, 0x00, 0x08, b'S', b'C', b'R', b'A', // authentication method
+            b'M', b'-', b'S', b'H', // rest of the packet
+        ];
+        let mut cur = Cursor::new(data);
+        let result = ConnectProperties::read(&mut cur);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+
+        // a user property pair running past the block
+        let data = [
+            0x04, // properties
+            0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v', // user property
+        ];
+        let mut cur = Cursor::new(data);
+        let result = WillProperties::read(&mut cur);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+    }
 
     #[test]
     fn test_protocol_name_and_version() {
@@ -297,6 +1005,15 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_invalid_protocol_name_length() {
+        let mut cur = Cursor::new([0x00, 0x05, b'M', b'Q', b'T', b'T', b'T', 0x05]);
+        let result = Connect::read(&mut cur);
+        let err = result.unwrap_err();
+        assert_eq!(err, Error::InvalidProtocolNameLength(5));
+        assert!(err.to_string().contains('5'), "{}", err);
+    }
+
     #[test]
     fn test_invalid_protocol_version() {
         let mut cur = Cursor::new([0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04]);
@@ -419,6 +1136,31 @@ mod tests {
         assert_eq!(written_result.unwrap().as_slice(), data);
     }
 
+    #[test]
+    fn test_peek_identity() {
+        let data = [
+            0x10, 0x24, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, // protocol version
+            0xC2, // Username=1, password=1, retain=0, qos=0, will=0, clean start=1, reserved=0
+            0x00, 0x18, // Keep alive - 24
+            0x03, // properties
+            0x21, 0x00, 0x0A, // receive maximum
+            0x00, 0x06, b'c', b'l', b'i', b'e', b'n', b't', // client id
+            0x00, 0x05, b'h', b'e', b'l', b'l', b'o', // username
+            0x00, 0x05, b'w', b'o', b'r', b'l', b'd', // password
+        ];
+        let mut cur = Cursor::new(data);
+        let header_result = FixedHeaderReader::read(&mut cur);
+        assert!(header_result.is_ok());
+
+        let result = Connect::peek_identity(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let (client_id, clean_start) = result.unwrap();
+        assert_eq!(client_id, "client");
+        assert!(clean_start);
+        // the credentials are not consumed
+        assert_eq!(cur.position(), 24);
+    }
+
     #[test]
     fn test_connect_packet_with_will_message() {
         let data = [
@@ -462,4 +1204,260 @@ mod tests {
         );
         assert_eq!(written_result.unwrap().as_slice(), data);
     }
+
+    #[test]
+    fn test_fits_within() {
+        let connect = Connect {
+            keep_alive: 60,
+            client_id: "client".to_string(),
+            will: Some(Will {
+                qos: 1,
+                topic: "status/client".to_string(),
+                payload: vec![0xAB; 1024],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let result = connect.write();
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let encoded_size = connect.encoded_size().unwrap();
+        assert_eq!(encoded_size as usize, result.unwrap().len());
+
+        assert!(!connect.fits_within(256));
+        assert!(!connect.fits_within(encoded_size - 1));
+        assert!(connect.fits_within(encoded_size));
+    }
+
+    #[test]
+    fn test_read_will_qos() {
+        let data = [
+            0x00, // will properties
+            0x00, 0x01, b'a', // will topic
+            0x00, 0x00, // will payload
+        ];
+        let mut cur = Cursor::new(data);
+        let result = Will::read(&mut cur, 0x1C);
+        assert_eq!(result.unwrap_err(), Error::InvalidWillQos);
+        assert_eq!(cur.position(), 0);
+
+        let mut cur = Cursor::new(data);
+        let result = Will::read(&mut cur, 0x14);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(result.unwrap().qos, 2);
+    }
+
+    #[test]
+    fn test_will_empty_payload_round_trip() {
+        let connect = Connect {
+            client_id: "client".to_string(),
+            will: Some(Will {
+                qos: 1,
+                retain: true,
+                topic: "status".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let result = connect.write();
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let encoded = result.unwrap();
+        // will properties, topic and a zero length payload
+        assert!(
+            encoded.ends_with(&[0x00, 0x00, 0x06, b's', b't', b'a', b't', b'u', b's', 0x00, 0x00])
+        );
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        assert!(FixedHeaderReader::read(&mut cur).is_ok());
+        let result = Connect::read(&mut cur);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let decoded = result.unwrap();
+        let will = decoded.will.as_ref().unwrap();
+        assert_eq!(will.qos, 1);
+        assert!(will.retain);
+        assert_eq!(will.topic, "status");
+        assert!(will.payload.is_empty());
+        assert_eq!(decoded.write().unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_will_without_properties_round_trip() {
+        let mut connect = Connect {
+            client_id: "c".to_string(),
+            will: Some(Will {
+                topic: "t".to_string(),
+                payload: vec![0x01],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let encoded = connect.write().unwrap();
+        // will property length 0x00, topic and payload
+        assert!(encoded.ends_with(&[0x00, 0x00, 0x01, b't', 0x00, 0x01, 0x01]));
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        assert!(FixedHeaderReader::read(&mut cur).is_ok());
+        let decoded = Connect::read(&mut cur).unwrap();
+        assert!(decoded.will.as_ref().unwrap().properties.is_none());
+        assert_eq!(decoded.write().unwrap(), encoded);
+
+        // an empty property block is written the same way and read back as
+        // no properties
+        connect.will.as_mut().unwrap().properties = Some(WillProperties::default());
+        assert_eq!(connect.write().unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_allows_problem_info() {
+        let mut props = ConnectProperties::default();
+        assert!(props.allows_problem_info());
+        props.request_problem_info = Some(false);
+        assert!(!props.allows_problem_info());
+        props.request_problem_info = Some(true);
+        assert!(props.allows_problem_info());
+    }
+
+    #[test]
+    fn test_validate_maximum_packet_size() {
+        let mut props = ConnectProperties::default();
+        assert!(props
+            .validate_maximum_packet_size(MIN_MAXIMUM_PACKET_SIZE)
+            .is_ok());
+
+        props.maximum_packet_size = Some(1);
+        // the check is opt-in
+        assert!(props.validate().is_ok());
+        let result = props.validate_maximum_packet_size(MIN_MAXIMUM_PACKET_SIZE);
+        assert_eq!(
+            result,
+            Err(Error::MaximumPacketSizeTooSmall(1, MIN_MAXIMUM_PACKET_SIZE))
+        );
+
+        props.maximum_packet_size = Some(MIN_MAXIMUM_PACKET_SIZE);
+        assert!(props
+            .validate_maximum_packet_size(MIN_MAXIMUM_PACKET_SIZE)
+            .is_ok());
+        assert!(props.validate_maximum_packet_size(1024).is_err());
+    }
+
+    #[test]
+    fn test_keep_alive_from_secs() {
+        assert_eq!(KeepAlive::from_secs(0), Ok(0));
+        assert_eq!(KeepAlive::from_secs(60), Ok(60));
+        assert_eq!(KeepAlive::from_secs(65535), Ok(65535));
+        assert_eq!(
+            KeepAlive::from_secs(65536),
+            Err(Error::InvalidKeepAlive(65536))
+        );
+        assert!(KeepAlive::from_secs(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_encoded_size_matches_written() {
+        let connects = [
+            Connect {
+                protocol_name: "MQTT",
+                protocol_version: 5,
+                client_id: "client".to_string(),
+                ..Default::default()
+            },
+            Connect {
+                protocol_name: "MQTT",
+                protocol_version: 5,
+                keep_alive: 60,
+                will: Some(Will {
+                    topic: "a/b".to_string(),
+                    payload: vec![0; 200],
+                    properties: Some(WillProperties {
+                        content_type: "text/plain".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                properties: Some(ConnectProperties {
+                    receive_maximum: Some(10),
+                    user_property: vec![("k".to_string(), "v".to_string())],
+                    ..Default::default()
+                }),
+                client_id: "client".to_string(),
+                user_name: "user".to_string(),
+                password: vec![0x01, 0x02],
+                ..Default::default()
+            },
+        ];
+        for connect in connects {
+            let mut w = CountingWriter::new();
+            let result = connect.write_to(&mut w);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            assert_eq!(w.count(), connect.encoded_size().unwrap() as u64);
+        }
+    }
+
+    #[test]
+    fn test_write_into_vec() {
+        let client_ids = ["first", "second", "third"];
+        let mut buf = vec![0xFF];
+        for client_id in client_ids {
+            let connect = Connect {
+                clean_start: true,
+                keep_alive: 30,
+                client_id: client_id.to_string(),
+                ..Default::default()
+            };
+            let result = connect.write_into_vec(&mut buf);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+        }
+        // the existing content is kept
+        assert_eq!(buf[0], 0xFF);
+
+        let mut cur = Cursor::new(&buf[1..]);
+        for client_id in client_ids {
+            let header_result = FixedHeaderReader::read(&mut cur);
+            assert!(header_result.is_ok(), "{}", header_result.unwrap_err());
+            let result = Connect::read(&mut cur);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            assert_eq!(result.unwrap().client_id, client_id);
+        }
+        assert_eq!(cur.position() as usize, buf.len() - 1);
+
+        // a failed encoding leaves the buffer untouched
+        let connect = Connect {
+            will: Some(Will {
+                qos: 3,
+                topic: "a".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let len = buf.len();
+        assert!(connect.write_into_vec(&mut buf).is_err());
+        assert_eq!(buf.len(), len);
+    }
+
+    #[test]
+    fn test_write_invalid_will() {
+        let mut connect = Connect {
+            client_id: "client".to_string(),
+            will: Some(Will {
+                retain: true,
+                payload: vec![0x01],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(connect.write().unwrap_err(), Error::InvalidWillRetain);
+
+        connect.will = Some(Will {
+            qos: 3,
+            topic: "status".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(connect.write().unwrap_err(), Error::InvalidWillQos);
+
+        connect.will = Some(Will {
+            retain: true,
+            topic: "status".to_string(),
+            ..Default::default()
+        });
+        assert!(connect.write().is_ok());
+    }
 }
@@ -1,5 +1,8 @@
+pub mod connack;
 pub mod connect;
 pub mod packet;
+pub mod publish;
+pub mod suback;
 
 #[cfg(test)]
 mod tests {}
@@ -1,6 +1,12 @@
-use mqttio::io::Reader;
+use std::io::Cursor;
+
+use mqttio::io::{Reader, VarUint32Size, Writer};
+use mqttio::options::DecodeOptions;
+use mqttio::properties::Properties;
+use num::FromPrimitive;
 
 use crate::errors::Error;
+use crate::topic::validate_subscribe_topic;
 
 // PacketType MQTT control packet type
 // MQTT 12.1.2
@@ -55,6 +61,7 @@ impl PacketType {
 // are defined here. The specific packet based error codes can be found in their
 // respective packet writer/reader
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ReasonCode {
     Success = 0x00, // CONNACK, PUBACK, PUBREC, PUBREL, PUBCOMP, UNSUBACK, AUTH
     NoMatchingSubscribers = 0x10, // PUBACK, PUBREC
@@ -113,21 +120,247 @@ impl ReasonCode {
     }
 }
 
+// ProtocolStateMachine tracks the packet order on a network connection. The
+// first packet sent by a client must be CONNECT and it must not be sent again,
+// any other packet type is permitted afterwards.
+// MQTT 3.1
+#[derive(Debug, Default)]
+pub struct ProtocolStateMachine {
+    connected: bool,
+}
+
+impl ProtocolStateMachine {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn expect_first(&mut self, packet_type: PacketType) -> Result<(), Error> {
+        match (self.connected, packet_type) {
+            (false, PacketType::CONNECT) => {
+                self.connected = true;
+                Ok(())
+            }
+            (false, _) => Err(Error::ProtocolError("the first packet must be CONNECT")),
+            (true, PacketType::CONNECT) => {
+                Err(Error::ProtocolError("CONNECT must be sent only once"))
+            }
+            (true, _) => Ok(()),
+        }
+    }
+}
+
 pub struct FixedHeaderReader {}
 
 impl FixedHeaderReader {
+    // read reads the first byte and the remaining length of the fixed header.
+    // The packet type 0 is reserved and forbidden on the wire.
     pub fn read<R: Reader>(r: &mut R) -> Result<(u8, u32), Error> {
         let byte0: u8 = r.read_u8()?;
+        if byte0 >> 4 == PacketType::RESERVED as u8 {
+            return Err(Error::ReservedPacketType);
+        }
         let remaining_len: u32 = r.read_varuint32()?;
         return Ok((byte0, remaining_len));
     }
+
+    // read_with_limit reads the fixed header of a packet that must not be
+    // larger than max bytes, e.g. the Maximum Packet Size, the fixed header
+    // included. The check is done before the rest of the packet is read.
+    pub fn read_with_limit<R: Reader>(r: &mut R, max: u32) -> Result<(u8, u32), Error> {
+        let (byte0, remaining_len) = FixedHeaderReader::read(r)?;
+        let size = 1 + VarUint32Size::size(remaining_len) as u64 + remaining_len as u64;
+        if size > max as u64 {
+            return Err(Error::PacketTooLarge(size, max));
+        }
+        Ok((byte0, remaining_len))
+    }
+}
+
+// verify_packet_length checks that a buffer holding exactly one packet is as
+// long as its fixed header declares, i.e. the remaining length equals the
+// bytes following the fixed header. Trailing or missing bytes are a malformed
+// packet.
+pub fn verify_packet_length(bytes: &[u8]) -> Result<(), Error> {
+    let mut cur = Cursor::new(bytes);
+    let (_, remaining_len) = FixedHeaderReader::read(&mut cur)?;
+    let header_len = cur.position() as usize;
+    if remaining_len as usize != bytes.len() - header_len {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(())
+}
+
+// write_remaining_length writes the remaining length of the fixed header and
+// returns the number of bytes written. The size of the encoded length is
+// computed up front by the packet writers with VarUint32Size, in debug builds
+// both are checked to agree.
+pub fn write_remaining_length<W: Writer>(w: &mut W, len: u32) -> Result<u32, Error> {
+    let mut buf = Cursor::new([0u8; 4]);
+    buf.write_varuint32(len)?;
+    let written = buf.position() as u32;
+    debug_assert_eq!(written, VarUint32Size::size(len));
+    w.write_internal(&buf.get_ref()[..written as usize])?;
+    Ok(written)
+}
+
+// validate_fixed_header_flags checks the flags in the low nibble of the first
+// byte of the fixed header against the values MQTT 2.1.3 reserves for the
+// packet type: 0b0010 for PUBREL, SUBSCRIBE and UNSUBSCRIBE and 0b0000 for the
+// rest. PUBLISH carries DUP, QoS and RETAIN there, which are checked by
+// validate_publish_flags. With lenient decoding the reserved bits are ignored
+// and the first byte is returned with the expected flags.
+pub fn validate_fixed_header_flags(byte0: u8, options: &DecodeOptions) -> Result<u8, Error> {
+    // all the 16 values of the upper nibble are valid packet types
+    let packet_type = PacketType::from_u8(byte0 >> 4).unwrap();
+    let expected = match packet_type {
+        PacketType::PUBLISH => return validate_publish_flags(byte0).map(|_| byte0),
+        PacketType::PUBREL | PacketType::SUBSCRIBE | PacketType::UNSUBSCRIBE => 0x02,
+        _ => 0x00,
+    };
+
+    let flags = byte0 & 0x0F;
+    if flags == expected {
+        return Ok(byte0);
+    }
+    if options.strict_reserved_bits {
+        return Err(Error::InvalidFixedHeaderFlags(packet_type.as_str(), flags));
+    }
+    Ok((byte0 & 0xF0) | expected)
+}
+
+// validate_publish_flags checks the flags of a PUBLISH fixed header, a QoS 0
+// message must have DUP clear (MQTT 3.3.1.1) and QoS 3 is not allowed
+// (MQTT 3.3.1.2).
+pub fn validate_publish_flags(byte0: u8) -> Result<(), Error> {
+    let qos = (byte0 >> 1) & 0x03;
+    if qos == 3 || (qos == 0 && byte0 & 0x08 != 0) {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(())
+}
+
+// publish_fixed_header_byte builds the first byte of a PUBLISH, DUP is never
+// set for a QoS 0 message.
+pub fn publish_fixed_header_byte(dup: bool, qos: u8, retain: bool) -> u8 {
+    let mut byte0 = (PacketType::PUBLISH as u8) << 4 | (qos & 0x03) << 1;
+    if dup && qos > 0 {
+        byte0 |= 0x08;
+    }
+    if retain {
+        byte0 |= 0x01;
+    }
+    byte0
+}
+
+// read_reason_and_properties reads the "reason code + properties" tail shared
+// by CONNACK, PUBACK, PUBREC, PUBREL, PUBCOMP, DISCONNECT and AUTH.
+// remaining_len is the number of bytes left in the packet for the tail. When it
+// is 0 the reason code is Success (0x00) and there are no properties, when it
+// is 1 only the reason code is present.
+pub fn read_reason_and_properties<R: Reader, P: Properties<Error = Error>>(
+    r: &mut R,
+    remaining_len: u32,
+) -> Result<(u8, Option<P>), Error> {
+    if remaining_len == 0 {
+        return Ok((ReasonCode::Success as u8, None));
+    }
+
+    let reason_code = r.read_u8()?;
+    if remaining_len == 1 {
+        return Ok((reason_code, None));
+    }
+
+    let properties = P::read_with_limit(r, remaining_len - 1)?;
+    Ok((reason_code, properties))
+}
+
+// write_reason_and_properties writes the "reason code + properties" tail in
+// its shortest form: nothing for Success without properties, the reason code
+// alone without properties. A tail decoded by read_reason_and_properties from
+// a short form is written back unchanged. Returns the number of bytes written.
+pub fn write_reason_and_properties<W: Writer, P: Properties<Error = Error>>(
+    w: &mut W,
+    reason_code: u8,
+    properties: Option<&P>,
+) -> Result<u32, Error> {
+    let property_len = match properties {
+        Some(p) => p.property_length()?,
+        None => 0,
+    };
+    if property_len == 0 {
+        if reason_code == ReasonCode::Success as u8 {
+            return Ok(0);
+        }
+        w.write_u8(reason_code)?;
+        return Ok(1);
+    }
+
+    w.write_u8(reason_code)?;
+    w.write_property_block(properties)?;
+    Ok(1 + VarUint32Size::size(property_len) + property_len)
+}
+
+// encode_reason_codes and decode_reason_codes handle the payload of SUBACK and
+// UNSUBACK, one reason code byte per topic filter of the request. A payload
+// with a different number of reason codes than the request had filters is a
+// malformed packet.
+pub fn encode_reason_codes(codes: &[u8]) -> Vec<u8> {
+    codes.to_vec()
+}
+
+pub fn decode_reason_codes(bytes: &[u8], count: usize) -> Result<Vec<u8>, Error> {
+    if bytes.len() != count {
+        return Err(Error::MalformedPacket);
+    }
+    Ok(bytes.to_vec())
+}
+
+// grant_subscription decides the SUBACK reason code of one topic filter: the
+// requested QoS clamped to the maximum QoS of the server, or the reason the
+// filter is rejected. Shared subscriptions ($share/...) are checked before
+// wildcards, so a shared wildcard filter is rejected as shared.
+pub fn grant_subscription(
+    filter: &str,
+    requested_qos: u8,
+    maximum_qos: u8,
+    wildcard_available: bool,
+    shared_available: bool,
+) -> Result<u8, ReasonCode> {
+    if validate_subscribe_topic(filter).is_err() {
+        return Err(ReasonCode::TopicFilterInvalid);
+    }
+    if !shared_available && filter.starts_with("$share/") {
+        return Err(ReasonCode::SharedSubscriptionsNotSupported);
+    }
+    if !wildcard_available && filter.contains(['+', '#']) {
+        return Err(ReasonCode::WildcardSubscriptionsNotSupported);
+    }
+    Ok(requested_qos.min(maximum_qos))
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use mqttio::options::DecodeOptions;
+
+    use crate::errors::Error;
+    use crate::packet::connect::ConnectProperties;
+    use crate::packet::suback::Suback;
+
+    use super::decode_reason_codes;
+    use super::encode_reason_codes;
+    use super::grant_subscription;
+    use super::publish_fixed_header_byte;
+    use super::read_reason_and_properties;
+    use super::validate_fixed_header_flags;
+    use super::verify_packet_length;
+    use super::write_reason_and_properties;
+    use super::write_remaining_length;
     use super::FixedHeaderReader;
+    use super::PacketType;
+    use super::ProtocolStateMachine;
+    use super::ReasonCode;
 
     #[test]
     fn test_fixed_header_read() {
@@ -142,4 +375,281 @@ mod tests {
         assert_eq!(hdr.0, 0x10);
         assert_eq!(hdr.1, 0x1B);
     }
+
+    #[test]
+    fn test_fixed_header_read_with_limit() {
+        // 1 + 1 + 0x1B bytes
+        let mut cur = Cursor::new(vec![0x10, 0x1B]);
+        assert_eq!(
+            FixedHeaderReader::read_with_limit(&mut cur, 29),
+            Ok((0x10, 0x1B))
+        );
+        let mut cur = Cursor::new(vec![0x10, 0x1B]);
+        let result = FixedHeaderReader::read_with_limit(&mut cur, 28);
+        assert_eq!(result, Err(Error::PacketTooLarge(29, 28)));
+        assert_eq!(result.unwrap_err().reason_code(), 0x95);
+
+        // the largest remaining length, 1 + 4 + 268435455 bytes
+        let mut cur = Cursor::new(vec![0x30, 0xFF, 0xFF, 0xFF, 0x7F]);
+        let result = FixedHeaderReader::read_with_limit(&mut cur, u32::MAX);
+        assert_eq!(result, Ok((0x30, 268435455)));
+        let mut cur = Cursor::new(vec![0x30, 0xFF, 0xFF, 0xFF, 0x7F]);
+        let result = FixedHeaderReader::read_with_limit(&mut cur, 1024);
+        assert_eq!(result, Err(Error::PacketTooLarge(268435460, 1024)));
+    }
+
+    #[test]
+    fn test_fixed_header_reserved_packet_type() {
+        for byte0 in [0x00, 0x02, 0x0F] {
+            let mut cur = Cursor::new(vec![byte0, 0x00]);
+            let result = FixedHeaderReader::read(&mut cur);
+            assert_eq!(result, Err(Error::ReservedPacketType));
+            assert_eq!(result.unwrap_err().reason_code(), 0x81);
+        }
+    }
+
+    #[test]
+    fn test_read_reason_and_properties() {
+        // no reason code, defaults to success
+        let mut cur = Cursor::new(vec![]);
+        let result = read_reason_and_properties::<_, ConnectProperties>(&mut cur, 0);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let (reason_code, props) = result.unwrap();
+        assert_eq!(reason_code, 0x00);
+        assert!(props.is_none());
+
+        // reason code only
+        let mut cur = Cursor::new(vec![0x10]);
+        let result = read_reason_and_properties::<_, ConnectProperties>(&mut cur, 1);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let (reason_code, props) = result.unwrap();
+        assert_eq!(reason_code, 0x10);
+        assert!(props.is_none());
+
+        // reason code and an empty property block
+        let mut cur = Cursor::new(vec![0x80, 0x00]);
+        let result = read_reason_and_properties::<_, ConnectProperties>(&mut cur, 2);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let (reason_code, props) = result.unwrap();
+        assert_eq!(reason_code, 0x80);
+        assert!(props.is_none());
+
+        // reason code and properties
+        let mut cur = Cursor::new(vec![0x87, 0x03, 0x21, 0x00, 0x0A]);
+        let result = read_reason_and_properties::<_, ConnectProperties>(&mut cur, 5);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let (reason_code, props) = result.unwrap();
+        assert_eq!(reason_code, 0x87);
+        assert!(props.is_some());
+        assert_eq!(cur.position(), 5);
+
+        // the property length claims more than the packet contains
+        let mut cur = Cursor::new(vec![0x87, 0x04, 0x21, 0x00, 0x0A]);
+        let result = read_reason_and_properties::<_, ConnectProperties>(&mut cur, 5);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+    }
+
+    #[test]
+    fn test_reason_codes_payload() {
+        let codes = [0x00, 0x02, 0x8F, 0x01];
+        let encoded = encode_reason_codes(&codes);
+        assert_eq!(encoded, codes);
+        assert!(encode_reason_codes(&[]).is_empty());
+        assert_eq!(decode_reason_codes(&encoded, 4).unwrap(), codes);
+        assert!(decode_reason_codes(&[], 0).unwrap().is_empty());
+
+        assert_eq!(
+            decode_reason_codes(&encoded, 3).unwrap_err(),
+            Error::MalformedPacket
+        );
+        assert_eq!(
+            decode_reason_codes(&encoded, 5).unwrap_err(),
+            Error::MalformedPacket
+        );
+    }
+
+    #[test]
+    fn test_grant_subscription() {
+        let grants: Vec<Result<u8, ReasonCode>> = [
+            ("a/b", 2),
+            ("a/+", 2),
+            ("a/#", 0),
+            ("$share/group/a/b", 1),
+            ("$share/group/a/+", 1),
+            ("a/b#", 1),
+        ]
+        .iter()
+        .map(|(filter, qos)| grant_subscription(filter, *qos, 1, false, false))
+        .collect();
+        assert_eq!(
+            Suback::from_grants(1, &grants).reason_codes(),
+            [0x01, 0xA2, 0xA2, 0x9E, 0x9E, 0x8F]
+        );
+
+        // everything supported, only the QoS is clamped
+        assert_eq!(grant_subscription("a/+", 2, 1, true, true), Ok(1));
+        assert_eq!(grant_subscription("a/+", 0, 2, true, true), Ok(0));
+        assert_eq!(
+            grant_subscription("$share/group/#", 2, 2, true, true),
+            Ok(2)
+        );
+        assert_eq!(
+            grant_subscription("$share/group/#", 2, 2, false, true),
+            Err(ReasonCode::WildcardSubscriptionsNotSupported)
+        );
+    }
+
+    #[test]
+    fn test_reason_and_properties_round_trip() {
+        let tails: [&[u8]; 4] = [
+            &[],                             // Success without properties
+            &[0x10],                         // reason code only
+            &[0x00, 0x03, 0x21, 0x00, 0x0A], // Success with properties
+            &[0x87, 0x03, 0x21, 0x00, 0x0A], // reason code and properties
+        ];
+        for tail in tails {
+            let mut cur = Cursor::new(tail);
+            let result =
+                read_reason_and_properties::<_, ConnectProperties>(&mut cur, tail.len() as u32);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            let (reason_code, props) = result.unwrap();
+
+            let mut cur = Cursor::new(Vec::new());
+            let result = write_reason_and_properties(&mut cur, reason_code, props.as_ref());
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            assert_eq!(result.unwrap() as usize, tail.len());
+            assert_eq!(cur.into_inner(), tail);
+        }
+
+        // an empty property block is not written
+        let mut cur = Cursor::new(Vec::new());
+        let props = ConnectProperties::default();
+        assert!(write_reason_and_properties(&mut cur, 0x80, Some(&props)).is_ok());
+        assert_eq!(cur.into_inner(), [0x80]);
+    }
+
+    #[test]
+    fn test_validate_fixed_header_flags() {
+        let strict = DecodeOptions::default();
+        let lenient = DecodeOptions {
+            strict_reserved_bits: false,
+            ..Default::default()
+        };
+
+        // PUBACK with the reserved bits set
+        let result = validate_fixed_header_flags(0x43, &strict);
+        assert_eq!(
+            result.unwrap_err(),
+            Error::InvalidFixedHeaderFlags("PUBACK", 0x03)
+        );
+        assert_eq!(validate_fixed_header_flags(0x43, &lenient).unwrap(), 0x40);
+
+        // PUBACK and SUBSCRIBE with the specified flags
+        for opts in [strict, lenient] {
+            assert_eq!(validate_fixed_header_flags(0x40, &opts).unwrap(), 0x40);
+            assert_eq!(validate_fixed_header_flags(0x82, &opts).unwrap(), 0x82);
+        }
+
+        // SUBSCRIBE requires 0b0010
+        assert!(validate_fixed_header_flags(0x80, &strict).is_err());
+        assert_eq!(validate_fixed_header_flags(0x80, &lenient).unwrap(), 0x82);
+
+        // PUBLISH flags are not reserved
+        assert_eq!(validate_fixed_header_flags(0x3B, &strict).unwrap(), 0x3B);
+    }
+
+    #[test]
+    fn test_publish_flags() {
+        let strict = DecodeOptions::default();
+        let lenient = DecodeOptions {
+            strict_reserved_bits: false,
+            ..Default::default()
+        };
+
+        for opts in [strict, lenient] {
+            // QoS 0 with DUP set
+            assert_eq!(
+                validate_fixed_header_flags(0x38, &opts).unwrap_err(),
+                Error::MalformedPacket
+            );
+            // QoS 3
+            assert_eq!(
+                validate_fixed_header_flags(0x36, &opts).unwrap_err(),
+                Error::MalformedPacket
+            );
+            assert_eq!(validate_fixed_header_flags(0x31, &opts).unwrap(), 0x31);
+            assert_eq!(validate_fixed_header_flags(0x3C, &opts).unwrap(), 0x3C);
+        }
+
+        assert_eq!(publish_fixed_header_byte(true, 0, true), 0x31);
+        assert_eq!(publish_fixed_header_byte(false, 0, false), 0x30);
+        assert_eq!(publish_fixed_header_byte(true, 1, false), 0x3A);
+        assert_eq!(publish_fixed_header_byte(true, 2, true), 0x3D);
+    }
+
+    #[test]
+    fn test_write_remaining_length() {
+        let lengths: [(u32, &[u8]); 8] = [
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16383, &[0xFF, 0x7F]),
+            (16384, &[0x80, 0x80, 0x01]),
+            (2097151, &[0xFF, 0xFF, 0x7F]),
+            (2097152, &[0x80, 0x80, 0x80, 0x01]),
+            (268435455, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (len, encoded) in lengths {
+            let mut cur = Cursor::new(Vec::new());
+            let result = write_remaining_length(&mut cur, len);
+            assert!(result.is_ok(), "{}", result.unwrap_err());
+            assert_eq!(result.unwrap(), encoded.len() as u32);
+            assert_eq!(cur.get_ref().as_slice(), encoded);
+        }
+
+        let mut cur = Cursor::new(Vec::new());
+        assert!(write_remaining_length(&mut cur, 268435456).is_err());
+        assert!(cur.get_ref().is_empty());
+    }
+
+    #[test]
+    fn test_verify_packet_length() {
+        // PINGREQ and PUBACK with the exact length
+        assert!(verify_packet_length(&[0xC0, 0x00]).is_ok());
+        assert!(verify_packet_length(&[0x40, 0x02, 0x00, 0x01]).is_ok());
+
+        // trailing garbage and a truncated packet
+        let result = verify_packet_length(&[0x40, 0x02, 0x00, 0x01, 0xFF]);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        let result = verify_packet_length(&[0x40, 0x02, 0x00]);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+
+        // two byte remaining length
+        let mut packet = vec![0x30, 0x80, 0x01];
+        packet.resize(3 + 128, 0x00);
+        assert!(verify_packet_length(&packet).is_ok());
+
+        // no complete fixed header
+        assert!(verify_packet_length(&[0x30, 0x80]).is_err());
+        assert!(verify_packet_length(&[]).is_err());
+    }
+
+    #[test]
+    fn test_protocol_state_machine() {
+        let mut state = ProtocolStateMachine::new();
+        assert!(matches!(
+            state.expect_first(PacketType::PUBLISH),
+            Err(Error::ProtocolError(_))
+        ));
+        assert!(state.expect_first(PacketType::DISCONNECT).is_err());
+
+        assert!(state.expect_first(PacketType::CONNECT).is_ok());
+        assert!(state.expect_first(PacketType::PUBLISH).is_ok());
+        assert!(state.expect_first(PacketType::SUBSCRIBE).is_ok());
+        assert!(state.expect_first(PacketType::DISCONNECT).is_ok());
+        assert!(matches!(
+            state.expect_first(PacketType::CONNECT),
+            Err(Error::ProtocolError(_))
+        ));
+    }
 }
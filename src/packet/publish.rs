@@ -0,0 +1,410 @@
+use crate::errors::Error;
+use crate::propertyio_derive::IOOperations;
+use crate::topic::validate_publish_topic;
+
+use mqttio::io::{
+    CountingReader, KeyValuePair, Reader, TeeReader, UTF8String, VarUint32Size, Writer,
+};
+use mqttio::options::DecodeOptions;
+use mqttio::properties::{
+    Properties, PropertyID, PropertyReader, PropertySize, PropertyWriter, RawProperty,
+};
+use num::FromPrimitive;
+
+use super::packet::{publish_fixed_header_byte, validate_publish_flags, write_remaining_length};
+
+#[derive(Debug, Default, IOOperations)]
+pub struct PublishProperties {
+    #[ioops(prop_id(PropertyID::PayloadFormatIndicator))]
+    payload_format_indicator: Option<bool>,
+    #[ioops(prop_id(PropertyID::MessageExpiryInterval))]
+    message_expiry_interval: Option<u32>,
+    #[ioops(prop_id(PropertyID::TopicAlias))]
+    topic_alias: Option<u16>,
+    #[ioops(prop_id(PropertyID::ResponseTopic))]
+    response_topic: String,
+    #[ioops(prop_id(PropertyID::CorrelationData))]
+    correlation_data: Vec<u8>,
+    #[ioops(prop_id(PropertyID::UserProperty))]
+    user_property: Vec<KeyValuePair>,
+    // a PUBLISH forwarded to a subscriber carries one identifier per matching
+    // subscription
+    #[ioops(prop_id(PropertyID::SubscriptionIdentifier), is_varuint32)]
+    subscription_identifier: Vec<u32>,
+    #[ioops(prop_id(PropertyID::ContentType))]
+    content_type: String,
+    #[ioops(unknown)]
+    unknown: Vec<RawProperty>,
+}
+
+#[derive(Debug, Default)]
+pub struct Publish {
+    dup: bool,
+    qos: u8,
+    retain: bool,
+    topic: String,
+    packet_id: Option<u16>,
+    properties: Option<PublishProperties>,
+    payload: Vec<u8>,
+}
+
+impl Publish {
+    // read reads the variable header and the payload of a PUBLISH. flags is
+    // the low nibble of the first byte of the fixed header and remaining_len
+    // its remaining length, the payload is what is left of the packet after
+    // the variable header.
+    pub fn read<R: Reader>(r: &mut R, flags: u8, remaining_len: u32) -> Result<Publish, Error> {
+        Publish::read_with_options(r, flags, remaining_len, &DecodeOptions::default())
+    }
+
+    pub fn read_with_options<R: Reader>(
+        r: &mut R,
+        flags: u8,
+        remaining_len: u32,
+        options: &DecodeOptions,
+    ) -> Result<Publish, Error> {
+        validate_publish_flags(flags)?;
+
+        let mut publish = Publish {
+            dup: (flags & 0x08) > 0,
+            qos: (flags >> 1) & 0x03,
+            retain: (flags & 0x01) > 0,
+            topic: r.read_utf8_string()?,
+            ..Default::default()
+        };
+        if validate_publish_topic(&publish.topic).is_err() {
+            return Err(Error::InvalidTopic);
+        }
+        let mut remaining_len = remaining_len
+            .checked_sub(UTF8String::size(&publish.topic))
+            .ok_or(Error::MalformedPacket)?;
+
+        // the packet identifier is only present for QoS 1 and 2 and must not
+        // be 0, MQTT 2.2.1
+        if publish.qos > 0 {
+            let packet_id = r.read_u16()?;
+            if packet_id == 0 {
+                return Err(Error::MalformedPacket);
+            }
+            publish.packet_id = Some(packet_id);
+            remaining_len = remaining_len.checked_sub(2).ok_or(Error::MalformedPacket)?;
+        }
+
+        let mut counter = CountingReader::new(r);
+        publish.properties =
+            PublishProperties::read_with_limit_and_options(&mut counter, remaining_len, options)?;
+        remaining_len -= counter.count() as u32;
+
+        publish.payload = r.read_vec(remaining_len as usize)?;
+
+        Ok(publish)
+    }
+
+    /// Encodes a PUBLISH from a borrowed topic and payload, e.g. when
+    /// forwarding a message, without building a Publish. DUP is not set.
+    pub fn encode_borrowed(
+        topic: &str,
+        payload: &[u8],
+        qos: u8,
+        packet_id: Option<u16>,
+        retain: bool,
+        props: Option<&PublishProperties>,
+    ) -> Result<Vec<u8>, Error> {
+        let publish = PublishRef {
+            dup: false,
+            qos,
+            retain,
+            topic,
+            packet_id,
+            properties: props,
+            payload,
+        };
+        let mut packet = Vec::new();
+        publish.write_to(&mut packet)?;
+        Ok(packet)
+    }
+
+    fn as_publish_ref(&self) -> PublishRef<'_> {
+        PublishRef {
+            dup: self.dup,
+            qos: self.qos,
+            retain: self.retain,
+            topic: &self.topic,
+            packet_id: self.packet_id,
+            properties: self.properties.as_ref(),
+            payload: &self.payload,
+        }
+    }
+
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut packet = Vec::new();
+        self.write_to(&mut packet)?;
+        Ok(packet)
+    }
+
+    /// Encodes the packet into a writer.
+    pub fn write_to<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+        self.as_publish_ref().write_to(w)
+    }
+}
+
+// PublishRef borrows the parts of a PUBLISH, both the owned Publish and
+// encode_borrowed are written through it.
+struct PublishRef<'a> {
+    dup: bool,
+    qos: u8,
+    retain: bool,
+    topic: &'a str,
+    packet_id: Option<u16>,
+    properties: Option<&'a PublishProperties>,
+    payload: &'a [u8],
+}
+
+impl PublishRef<'_> {
+    fn property_length(&self) -> Result<u32, Error> {
+        self.properties.map_or(Ok(0), Properties::property_length)
+    }
+
+    fn remaining_length(&self) -> Result<u32, Error> {
+        let property_len = self.property_length()?;
+
+        // summed as u64 so that a payload close to 4 GiB cannot wrap around
+        let mut remaining_len = 2
+            + self.topic.len() as u64
+            + VarUint32Size::size(property_len) as u64
+            + property_len as u64
+            + self.payload.len() as u64;
+        if self.packet_id.is_some() {
+            remaining_len += 2;
+        }
+        u32::try_from(remaining_len).map_err(Error::InvalidRemaningLength)
+    }
+
+    // validate checks that the packet identifier is present exactly when the
+    // QoS requires one.
+    fn validate(&self) -> Result<(), Error> {
+        match (self.qos, self.packet_id) {
+            (3.., _) => Err(Error::InvalidPublishQos(self.qos)),
+            (0, Some(_)) => Err(Error::ProtocolError(
+                "packet identifier set on a QoS 0 PUBLISH",
+            )),
+            (1 | 2, None | Some(0)) => Err(Error::ProtocolError(
+                "QoS 1 and 2 PUBLISH require a non-zero packet identifier",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_to<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+        self.validate()?;
+        let remaining_len = self.remaining_length()?;
+
+        w.write_u8(publish_fixed_header_byte(self.dup, self.qos, self.retain))?;
+        write_remaining_length(w, remaining_len)?;
+
+        w.write_utf8_string(self.topic)?;
+        if let Some(packet_id) = self.packet_id {
+            w.write_u16(packet_id)?;
+        }
+        w.write_property_block(self.properties)?;
+        w.write_internal(self.payload)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::errors::Error;
+    use crate::packet::packet::FixedHeaderReader;
+
+    use super::{Publish, PublishProperties};
+
+    fn round_trip(publish: &Publish) -> Publish {
+        let result = publish.write();
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let encoded = result.unwrap();
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        let (byte0, remaining_len) = FixedHeaderReader::read(&mut cur).unwrap();
+        assert_eq!(byte0 >> 4, 0x03);
+        let result = Publish::read(&mut cur, byte0 & 0x0F, remaining_len);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(cur.position() as usize, encoded.len());
+
+        let decoded = result.unwrap();
+        assert_eq!(decoded.write().unwrap(), encoded);
+        decoded
+    }
+
+    #[test]
+    fn test_publish_qos0() {
+        let publish = Publish {
+            retain: true,
+            topic: "a/b".to_string(),
+            payload: b"hello".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(
+            publish.write().unwrap(),
+            [
+                0x31, 0x0B, // fixed header
+                0x00, 0x03, b'a', b'/', b'b', // topic
+                0x00, // properties
+                b'h', b'e', b'l', b'l', b'o', // payload
+            ]
+        );
+
+        let decoded = round_trip(&publish);
+        assert_eq!(decoded.qos, 0);
+        assert!(decoded.retain);
+        assert!(!decoded.dup);
+        assert_eq!(decoded.packet_id, None);
+        assert_eq!(decoded.topic, "a/b");
+        assert_eq!(decoded.payload, b"hello");
+    }
+
+    #[test]
+    fn test_publish_qos1() {
+        let publish = Publish {
+            dup: true,
+            qos: 1,
+            retain: false,
+            topic: "a/b".to_string(),
+            packet_id: Some(0x1234),
+            properties: Some(PublishProperties {
+                message_expiry_interval: Some(60),
+                topic_alias: Some(3),
+                subscription_identifier: vec![1, 268435455],
+                user_property: vec![("k".to_string(), "v".to_string())],
+                ..Default::default()
+            }),
+            payload: Vec::new(),
+        };
+        let encoded = publish.write().unwrap();
+        assert_eq!(encoded[0], 0x3A);
+        assert_eq!(&encoded[7..9], [0x12, 0x34]);
+
+        let decoded = round_trip(&publish);
+        assert_eq!(decoded.qos, 1);
+        assert!(decoded.dup);
+        assert_eq!(decoded.packet_id, Some(0x1234));
+        assert!(decoded.payload.is_empty());
+        let props = decoded.properties.unwrap();
+        assert_eq!(props.message_expiry_interval, Some(60));
+        assert_eq!(props.topic_alias, Some(3));
+        assert_eq!(props.subscription_identifier, vec![1, 268435455]);
+    }
+
+    #[test]
+    fn test_publish_encode_borrowed() {
+        let props = PublishProperties {
+            content_type: "text/plain".to_string(),
+            subscription_identifier: vec![7],
+            ..Default::default()
+        };
+        let topic = "a/b";
+        let payload = b"forwarded".as_slice();
+
+        for (qos, packet_id, retain) in
+            [(0, None, false), (1, Some(10), true), (2, Some(11), false)]
+        {
+            let encoded =
+                Publish::encode_borrowed(topic, payload, qos, packet_id, retain, Some(&props))
+                    .unwrap();
+            let publish = Publish {
+                qos,
+                retain,
+                topic: topic.to_string(),
+                packet_id,
+                properties: Some(PublishProperties {
+                    content_type: props.content_type.clone(),
+                    subscription_identifier: props.subscription_identifier.clone(),
+                    ..Default::default()
+                }),
+                payload: payload.to_vec(),
+                ..Default::default()
+            };
+            assert_eq!(encoded, publish.write().unwrap());
+        }
+
+        let encoded = Publish::encode_borrowed(topic, &[], 0, None, false, None).unwrap();
+        let publish = Publish {
+            topic: topic.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(encoded, publish.write().unwrap());
+
+        let result = Publish::encode_borrowed(topic, payload, 1, None, false, None);
+        assert!(matches!(result, Err(Error::ProtocolError(_))));
+    }
+
+    #[test]
+    fn test_publish_write_invalid() {
+        let mut publish = Publish {
+            topic: "a".to_string(),
+            packet_id: Some(1),
+            ..Default::default()
+        };
+        assert!(matches!(publish.write(), Err(Error::ProtocolError(_))));
+
+        publish.qos = 3;
+        assert_eq!(publish.write().unwrap_err(), Error::InvalidPublishQos(3));
+
+        publish.qos = 2;
+        publish.packet_id = None;
+        assert!(matches!(publish.write(), Err(Error::ProtocolError(_))));
+
+        // DUP is not sent for QoS 0
+        let publish = Publish {
+            dup: true,
+            topic: "a".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(publish.write().unwrap()[0], 0x30);
+    }
+
+    #[test]
+    fn test_publish_read_invalid() {
+        let data = [0x00, 0x01, b'a', 0x00, 0x01, 0x00];
+        // QoS 3
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x06, data.len() as u32);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x0F, data.len() as u32);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+
+        // DUP on a QoS 0 message, MQTT 3.3.1.1
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x08, data.len() as u32);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x09, data.len() as u32);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+        // nothing is consumed before the flags are rejected
+        assert_eq!(cur.position(), 0);
+
+        // packet identifier 0
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x02, data.len() as u32);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let data = [0x00, 0x01, b'a', 0x00, 0x00, 0x00];
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x02, data.len() as u32);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+
+        // wildcard in the topic name
+        let data = [0x00, 0x01, b'+', 0x00];
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x00, data.len() as u32);
+        assert_eq!(result.unwrap_err(), Error::InvalidTopic);
+
+        // the properties run past the remaining length
+        let data = [0x00, 0x01, b'a', 0x03, 0x23, 0x00, 0x01];
+        let mut cur = Cursor::new(data);
+        let result = Publish::read(&mut cur, 0x00, 5);
+        assert_eq!(result.unwrap_err(), Error::MalformedPacket);
+    }
+}
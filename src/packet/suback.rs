@@ -0,0 +1,283 @@
+use crate::errors::Error;
+use crate::propertyio_derive::IOOperations;
+
+use mqttio::io::{CountingReader, KeyValuePair, Reader, TeeReader, VarUint32Size, Writer};
+use mqttio::options::DecodeOptions;
+use mqttio::properties::{Properties, PropertyID, PropertyReader, PropertySize, PropertyWriter};
+use num::FromPrimitive;
+
+use super::packet::{encode_reason_codes, write_remaining_length, PacketType, ReasonCode};
+
+// SubackProperties are the properties of SUBACK and of UNSUBACK, both allow
+// only a reason string and user properties.
+// MQTT 3.9.2.1, 3.11.2.1
+#[derive(Debug, Default, IOOperations)]
+pub struct SubackProperties {
+    #[ioops(prop_id(PropertyID::ReasonString))]
+    reason_string: String,
+    #[ioops(prop_id(PropertyID::UserProperty))]
+    user_property: Vec<KeyValuePair>,
+}
+
+#[derive(Debug, Default)]
+pub struct Suback {
+    packet_id: u16,
+    properties: Option<SubackProperties>,
+    reason_codes: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Unsuback {
+    packet_id: u16,
+    properties: Option<SubackProperties>,
+    reason_codes: Vec<u8>,
+}
+
+// read_ack reads the packet identifier, the properties and the reason codes
+// shared by SUBACK and UNSUBACK, the reason codes are what is left of the
+// packet after the properties.
+fn read_ack<R: Reader>(
+    r: &mut R,
+    remaining_len: u32,
+) -> Result<(u16, Option<SubackProperties>, Vec<u8>), Error> {
+    let packet_id = r.read_u16()?;
+    if packet_id == 0 {
+        return Err(Error::MalformedPacket);
+    }
+    let mut remaining_len = remaining_len.checked_sub(2).ok_or(Error::MalformedPacket)?;
+
+    let mut counter = CountingReader::new(r);
+    let properties = SubackProperties::read_with_limit(&mut counter, remaining_len)?;
+    remaining_len -= counter.count() as u32;
+
+    let reason_codes = r.read_vec(remaining_len as usize)?;
+    Ok((packet_id, properties, reason_codes))
+}
+
+fn write_ack<W: Writer>(
+    w: &mut W,
+    packet_type: PacketType,
+    packet_id: u16,
+    properties: Option<&SubackProperties>,
+    reason_codes: &[u8],
+) -> Result<(), Error> {
+    let property_len = properties.map_or(Ok(0), Properties::property_length)?;
+    let remaining_len = 2
+        + VarUint32Size::size(property_len) as u64
+        + property_len as u64
+        + reason_codes.len() as u64;
+    let remaining_len = u32::try_from(remaining_len).map_err(Error::InvalidRemaningLength)?;
+
+    w.write_u8((packet_type as u8) << 0x04)?;
+    write_remaining_length(w, remaining_len)?;
+
+    w.write_u16(packet_id)?;
+    w.write_property_block(properties)?;
+    w.write_internal(&encode_reason_codes(reason_codes))?;
+    Ok(())
+}
+
+impl Suback {
+    // from_grants builds the SUBACK for a SUBSCRIBE, one reason code per
+    // requested topic filter in the order of the SUBSCRIBE packet. A granted
+    // subscription yields its maximum QoS (0x00, 0x01 or 0x02), a rejected one
+    // the failure reason given for it.
+    // MQTT 3.9.3
+    pub fn from_grants(packet_id: u16, grants: &[Result<u8, ReasonCode>]) -> Suback {
+        let reason_codes = grants
+            .iter()
+            .map(|grant| match grant {
+                Ok(qos) => *qos,
+                Err(reason) => *reason as u8,
+            })
+            .collect();
+        Suback {
+            packet_id,
+            properties: None,
+            reason_codes,
+        }
+    }
+
+    pub fn reason_codes(&self) -> &[u8] {
+        &self.reason_codes
+    }
+
+    // read reads the variable header and the payload of a SUBACK,
+    // remaining_len is the remaining length of its fixed header.
+    pub fn read<R: Reader>(r: &mut R, remaining_len: u32) -> Result<Suback, Error> {
+        let (packet_id, properties, reason_codes) = read_ack(r, remaining_len)?;
+        Ok(Suback {
+            packet_id,
+            properties,
+            reason_codes,
+        })
+    }
+
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut packet = Vec::new();
+        self.write_to(&mut packet)?;
+        Ok(packet)
+    }
+
+    /// Encodes the packet into a writer.
+    pub fn write_to<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+        write_ack(
+            w,
+            PacketType::SUBACK,
+            self.packet_id,
+            self.properties.as_ref(),
+            &self.reason_codes,
+        )
+    }
+}
+
+impl Unsuback {
+    // from_results builds the UNSUBACK for an UNSUBSCRIBE, one reason code per
+    // topic filter in the order of the UNSUBSCRIBE packet: Success (0x00) for
+    // a removed subscription, the reason given for it otherwise.
+    // MQTT 3.11.3
+    pub fn from_results(packet_id: u16, results: &[Result<(), ReasonCode>]) -> Unsuback {
+        let reason_codes = results
+            .iter()
+            .map(|result| match result {
+                Ok(()) => ReasonCode::Success as u8,
+                Err(reason) => *reason as u8,
+            })
+            .collect();
+        Unsuback {
+            packet_id,
+            properties: None,
+            reason_codes,
+        }
+    }
+
+    pub fn reason_codes(&self) -> &[u8] {
+        &self.reason_codes
+    }
+
+    // read reads the variable header and the payload of an UNSUBACK,
+    // remaining_len is the remaining length of its fixed header.
+    pub fn read<R: Reader>(r: &mut R, remaining_len: u32) -> Result<Unsuback, Error> {
+        let (packet_id, properties, reason_codes) = read_ack(r, remaining_len)?;
+        Ok(Unsuback {
+            packet_id,
+            properties,
+            reason_codes,
+        })
+    }
+
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        let mut packet = Vec::new();
+        self.write_to(&mut packet)?;
+        Ok(packet)
+    }
+
+    /// Encodes the packet into a writer.
+    pub fn write_to<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+        write_ack(
+            w,
+            PacketType::UNSUBACK,
+            self.packet_id,
+            self.properties.as_ref(),
+            &self.reason_codes,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use num::FromPrimitive;
+
+    use crate::errors::Error;
+    use crate::packet::packet::{FixedHeaderReader, PacketType, ReasonCode};
+
+    use super::{Suback, SubackProperties, Unsuback};
+
+    #[test]
+    fn test_suback_from_grants() {
+        let grants = [
+            Ok(0),
+            Ok(2),
+            Err(ReasonCode::TopicFilterInvalid),
+            Ok(1),
+            Err(ReasonCode::NotAuthorized),
+        ];
+        let suback = Suback::from_grants(0x1234, &grants);
+        assert_eq!(suback.reason_codes(), [0x00, 0x02, 0x8F, 0x01, 0x87]);
+        assert_eq!(
+            suback.write().unwrap(),
+            [
+                0x90, 0x08, // fixed header
+                0x12, 0x34, // packet identifier
+                0x00, // properties
+                0x00, 0x02, 0x8F, 0x01, 0x87, // reason codes
+            ]
+        );
+        assert!(Suback::from_grants(1, &[]).reason_codes().is_empty());
+    }
+
+    #[test]
+    fn test_suback_round_trip() {
+        let mut suback = Suback::from_grants(7, &[Ok(1), Err(ReasonCode::TopicFilterInvalid)]);
+        suback.properties = Some(SubackProperties {
+            reason_string: "invalid filter".to_string(),
+            user_property: vec![("k".to_string(), "v".to_string())],
+        });
+        let encoded = suback.write().unwrap();
+
+        let mut cur = Cursor::new(encoded.as_slice());
+        let (byte0, remaining_len) = FixedHeaderReader::read(&mut cur).unwrap();
+        assert_eq!(PacketType::from_u8(byte0 >> 4), Some(PacketType::SUBACK));
+        let result = Suback::read(&mut cur, remaining_len);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(cur.position() as usize, encoded.len());
+
+        let decoded = result.unwrap();
+        assert_eq!(decoded.packet_id, 7);
+        assert_eq!(decoded.reason_codes(), [0x01, 0x8F]);
+        let props = decoded.properties.as_ref().unwrap();
+        assert_eq!(props.reason_string, "invalid filter");
+        assert_eq!(decoded.write().unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_unsuback_from_results() {
+        let results = [
+            Ok(()),
+            Err(ReasonCode::NotAuthorized),
+            Ok(()),
+            Err(ReasonCode::TopicFilterInvalid),
+        ];
+        let unsuback = Unsuback::from_results(1, &results);
+        assert_eq!(unsuback.reason_codes(), [0x00, 0x87, 0x00, 0x8F]);
+
+        let encoded = unsuback.write().unwrap();
+        assert_eq!(encoded[0], 0xB0);
+        let mut cur = Cursor::new(encoded.as_slice());
+        let (_, remaining_len) = FixedHeaderReader::read(&mut cur).unwrap();
+        let decoded = Unsuback::read(&mut cur, remaining_len).unwrap();
+        assert_eq!(decoded.packet_id, 1);
+        assert!(decoded.properties.is_none());
+        assert_eq!(decoded.reason_codes(), unsuback.reason_codes());
+        assert!(Unsuback::from_results(1, &[]).reason_codes().is_empty());
+    }
+
+    #[test]
+    fn test_suback_read_invalid() {
+        // packet identifier 0
+        let mut cur = Cursor::new([0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            Suback::read(&mut cur, 4).unwrap_err(),
+            Error::MalformedPacket
+        );
+
+        // the properties run past the remaining length
+        let mut cur = Cursor::new([0x00, 0x01, 0x04, 0x1F, 0x00, 0x01, b'a']);
+        assert_eq!(
+            Suback::read(&mut cur, 4).unwrap_err(),
+            Error::MalformedPacket
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use std::collections::VecDeque;
 use std::error::Error;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex;
+use std::time::Instant;
 
 const ERR_QUEUE_CLOSED: &'static str = "queue is closed";
 
@@ -23,8 +25,13 @@ impl<T> SyncQueue<T> {
         }
     }
 
+    /// Closes the queue and wakes up every consumer waiting for a value
     pub fn close(&self) {
+        // the flag is set under the lock so that a consumer cannot miss the
+        // wake up between checking the flag and waiting
+        let _data = self.data.lock().unwrap();
         self.closed.store(true, Ordering::Relaxed);
+        self.cv.notify_all();
     }
 
     pub fn push(&self, value: T) -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -53,6 +60,50 @@ impl<T> SyncQueue<T> {
         return Ok(data.pop_front().unwrap());
     }
 
+    /// Waits for a value until the deadline. Returns Ok(None) when the
+    /// deadline passes with the queue still empty, a deadline in the past
+    /// only checks the queue once.
+    pub fn pop_deadline(
+        &self,
+        deadline: Instant,
+    ) -> Result<Option<T>, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.lock().unwrap();
+        let mut closed = self.closed.load(Ordering::Relaxed);
+        while data.is_empty() && !closed {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            data = self.cv.wait_timeout(data, deadline - now).unwrap().0;
+            closed = self.closed.load(Ordering::Relaxed);
+        }
+
+        if closed {
+            return Err(ERR_QUEUE_CLOSED.into());
+        }
+
+        Ok(data.pop_front())
+    }
+
+    /// Waits until at least one value is queued and pops up to `n` values in
+    /// FIFO order under a single lock. The closed error is returned only when
+    /// the queue is closed and empty.
+    pub fn pop_up_to(&self, n: usize) -> Result<Vec<T>, Box<dyn Error + Send + Sync>> {
+        let mut data = self.data.lock().unwrap();
+        let mut closed = self.closed.load(Ordering::Relaxed);
+        while data.is_empty() && !closed {
+            data = self.cv.wait(data).unwrap();
+            closed = self.closed.load(Ordering::Relaxed);
+        }
+
+        if data.is_empty() {
+            return Err(ERR_QUEUE_CLOSED.into());
+        }
+
+        let count = n.min(data.len());
+        Ok(data.drain(..count).collect())
+    }
+
     pub fn len(&self) -> usize {
         let data = self.data.lock().unwrap();
         data.len()
@@ -69,10 +120,86 @@ impl<T> SyncQueue<T> {
     }
 }
 
+/// The sending half of a queue created with [`channel`]. Senders can be
+/// cloned to push from several producers.
+#[derive(Debug)]
+pub struct Sender<T> {
+    queue: Arc<SyncQueue<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, value: T) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.queue.push(value)
+    }
+
+    pub fn close(&self) {
+        self.queue.close();
+    }
+}
+
+/// The receiving half of a queue created with [`channel`].
+#[derive(Debug)]
+pub struct Receiver<T> {
+    queue: Arc<SyncQueue<T>>,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> Result<T, Box<dyn Error + Send + Sync>> {
+        self.queue.pop()
+    }
+
+    pub fn recv_deadline(
+        &self,
+        deadline: Instant,
+    ) -> Result<Option<T>, Box<dyn Error + Send + Sync>> {
+        self.queue.pop_deadline(deadline)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Creates a queue and returns the sender and receiver sharing it
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let queue = Arc::new(SyncQueue::new());
+    (
+        Sender {
+            queue: queue.clone(),
+        },
+        Receiver { queue },
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{sync::Arc, thread};
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
 
+    use super::channel;
     use super::SyncQueue;
     use crate::syncqueue::ERR_QUEUE_CLOSED;
 
@@ -168,4 +295,106 @@ mod tests {
 
         assert!(queue.is_empty())
     }
+
+    #[test]
+    fn test_channel_multiple_senders() {
+        let (tx, rx) = channel::<i32>();
+        let mut handles = Vec::new();
+        for t in 0..4 {
+            let tx = tx.clone();
+            handles.push(thread::spawn(move || {
+                for n in 0..25 {
+                    assert!(tx.send(t * 25 + n).is_ok());
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(rx.len(), 100);
+
+        let mut received: Vec<i32> = (0..100).map(|_| rx.recv().unwrap()).collect();
+        received.sort();
+        assert_eq!(received, (0..100).collect::<Vec<i32>>());
+        assert!(rx.is_empty());
+
+        tx.close();
+        assert!(tx.send(1).is_err());
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn test_recv_deadline() {
+        let (tx, rx) = channel::<i32>();
+
+        // a deadline in the past returns immediately
+        let past = Instant::now() - Duration::from_millis(10);
+        assert_eq!(rx.recv_deadline(past).unwrap(), None);
+
+        assert!(tx.send(1).is_ok());
+        assert_eq!(rx.recv_deadline(past).unwrap(), Some(1));
+
+        let tx2 = tx.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            assert!(tx2.send(2).is_ok());
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(rx.recv_deadline(deadline).unwrap(), Some(2));
+        t.join().unwrap();
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(20);
+        assert_eq!(rx.recv_deadline(deadline).unwrap(), None);
+        assert!(Instant::now() >= deadline);
+
+        tx.close();
+        assert!(rx.recv_deadline(past).is_err());
+    }
+
+    #[test]
+    fn test_pop_up_to() {
+        let queue: SyncQueue<i32> = SyncQueue::new();
+        for n in 1..6 {
+            assert!(queue.push(n).is_ok());
+        }
+        assert_eq!(queue.pop_up_to(3).unwrap(), vec![1, 2, 3]);
+        assert_eq!(queue.pop_up_to(3).unwrap(), vec![4, 5]);
+        assert!(queue.is_empty());
+
+        // the remaining values are handed out after closing
+        assert!(queue.push(6).is_ok());
+        queue.close();
+        assert_eq!(queue.pop_up_to(3).unwrap(), vec![6]);
+        assert!(queue.pop_up_to(3).is_err());
+
+        // waits for a value pushed by another thread
+        let queue = Arc::new(SyncQueue::<i32>::new());
+        let q1 = queue.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            assert!(q1.push(7).is_ok());
+        });
+        assert_eq!(queue.pop_up_to(3).unwrap(), vec![7]);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_wakes_waiters() {
+        let queue = Arc::new(SyncQueue::<i32>::new());
+        let (tx, rx) = channel::<i32>();
+
+        let q1 = queue.clone();
+        let t1 = thread::spawn(move || q1.pop_up_to(3));
+        let t2 = thread::spawn(move || rx.recv());
+
+        thread::sleep(Duration::from_millis(20));
+        queue.close();
+        tx.close();
+
+        let result = t1.join().unwrap();
+        assert_eq!(format!("{}", result.unwrap_err()), ERR_QUEUE_CLOSED);
+        let result = t2.join().unwrap();
+        assert_eq!(format!("{}", result.unwrap_err()), ERR_QUEUE_CLOSED);
+    }
 }
@@ -1,7 +1,14 @@
-use std::{rc::Rc, sync::RwLock};
+use std::{
+    collections::HashSet,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
 
 use crate::{
-    errors::{PublishTopicValidationError, SubscribeTopicValidationError},
+    errors::{Error, PublishTopicValidationError, SubscribeTopicValidationError},
     trie::Trie,
 };
 
@@ -10,13 +17,27 @@ pub fn validate_publish_topic(topic: &str) -> Result<(), PublishTopicValidationE
         return Err(PublishTopicValidationError::TopicLenTooLong);
     }
 
-    if let Some(_) = topic.chars().find(|c| *c == '#' || *c == '+') {
-        return Err(PublishTopicValidationError::InvalidTopic);
+    if let Some(c) = topic.chars().find(|c| *c == '#' || *c == '+') {
+        return Err(PublishTopicValidationError::InvalidTopic {
+            topic: topic.to_string(),
+            ch: c,
+        });
     }
 
     return Ok(());
 }
 
+/// Validates a batch of publish topics, stopping at the first invalid one.
+/// The error carries the index of that topic in the batch.
+pub fn validate_publish_topics(
+    topics: &[&str],
+) -> Result<(), (usize, PublishTopicValidationError)> {
+    for (i, topic) in topics.iter().enumerate() {
+        validate_publish_topic(topic).map_err(|e| (i, e))?;
+    }
+    Ok(())
+}
+
 pub fn validate_subscribe_topic(topic: &str) -> Result<(), SubscribeTopicValidationError> {
     if topic.is_empty() {
         return Err(SubscribeTopicValidationError::EmptySubscriptionTopic);
@@ -34,11 +55,17 @@ pub fn validate_subscribe_topic(topic: &str) -> Result<(), SubscribeTopicValidat
             if (i != 0 && previous_char != '/')
                 || (i < topic_len - 1 && topic.chars().nth(i + 1).unwrap() != '/')
             {
-                return Err(SubscribeTopicValidationError::InvalidTopic(c));
+                return Err(SubscribeTopicValidationError::InvalidTopic {
+                    topic: topic.to_string(),
+                    ch: c,
+                });
             }
         } else if c == '#' {
             if (i != 0 && previous_char != '/') || (i < (topic_len - 1)) {
-                return Err(SubscribeTopicValidationError::InvalidTopic(c));
+                return Err(SubscribeTopicValidationError::InvalidTopic {
+                    topic: topic.to_string(),
+                    ch: c,
+                });
             }
         }
         previous_char = c;
@@ -47,55 +74,366 @@ pub fn validate_subscribe_topic(topic: &str) -> Result<(), SubscribeTopicValidat
     return Ok(());
 }
 
+// validate_shared_subscription checks the "$share/{ShareName}/{filter}" form,
+// the share name must be non-empty and free of wildcards and a filter must
+// follow it. MQTT 4.8.2
+fn validate_shared_subscription(topic: &str) -> Result<(), SubscribeTopicValidationError> {
+    let rest = match topic.strip_prefix("$share/") {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    match rest.split_once('/') {
+        Some((name, filter))
+            if !name.is_empty() && !name.contains(['+', '#']) && !filter.is_empty() =>
+        {
+            Ok(())
+        }
+        _ => Err(SubscribeTopicValidationError::InvalidSharedSubscription(
+            topic.to_string(),
+        )),
+    }
+}
+
+/// Validates every topic filter of a SUBSCRIBE without subscribing, so that
+/// the SUBACK reason codes can be decided before any subscription is made.
+/// Returns one result per filter, in order.
+pub fn validate_subscribe_topics(
+    topics: &[&str],
+) -> Vec<Result<(), SubscribeTopicValidationError>> {
+    topics
+        .iter()
+        .map(|topic| {
+            validate_subscribe_topic(topic)?;
+            validate_shared_subscription(topic)
+        })
+        .collect()
+}
+
+/// Returns the form in which a topic filter is keyed by the subscription trie,
+/// which is the filter itself.
+///
+/// Every level is kept as is: empty levels are significant (`foo//bar` has
+/// three levels) and so are leading and trailing separators. No case folding
+/// or whitespace trimming is done.
+pub fn canonicalize_filter(filter: &str) -> String {
+    filter.to_string()
+}
+
+/// Detects topic filters that are listed more than once, e.g. in a single
+/// SUBSCRIBE. In strict mode the first repeated filter is reported as
+/// `Error::DuplicateTopicFilter`, otherwise the duplicates are collapsed into
+/// the position of the first occurrence, keeping the options of the last one.
+pub fn dedup_topic_filters<T>(
+    filters: Vec<(String, T)>,
+    strict: bool,
+) -> Result<Vec<(String, T)>, Error> {
+    let mut deduped: Vec<(String, T)> = Vec::with_capacity(filters.len());
+    for (filter, options) in filters {
+        let canonical = canonicalize_filter(&filter);
+        match deduped.iter_mut().find(|(f, _)| *f == canonical) {
+            Some(_) if strict => return Err(Error::DuplicateTopicFilter(filter)),
+            Some(existing) => existing.1 = options,
+            None => deduped.push((canonical, options)),
+        }
+    }
+    Ok(deduped)
+}
+
+/// Reports whether a topic name matches a single topic filter, without
+/// building a trie. An invalid filter or topic name never matches.
+///
+/// The MQTT wildcard rules are applied level by level: '+' matches exactly
+/// one level, which may be empty, and '#' matches the rest of the levels
+/// including the parent level, so `foo/#` matches `foo`. A filter starting
+/// with a wildcard does not match topic names beginning with '$'.
+pub fn topic_matches_filter(filter: &str, topic: &str) -> bool {
+    if validate_subscribe_topic(filter).is_err() || validate_publish_topic(topic).is_err() {
+        return false;
+    }
+
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    levels_match(filter, topic)
+}
+
+// levels_match applies the wildcards of a filter to a topic level by level,
+// without validating either of them.
+fn levels_match(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        if filter_level == "#" {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(level) if filter_level == "+" || filter_level == level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Reports whether there is a topic name that both filters would match,
+/// e.g. `a/+` and `a/b` overlap while `a/b` and `a/c` do not. Invalid
+/// filters never overlap.
+pub fn filters_overlap(a: &str, b: &str) -> bool {
+    if validate_subscribe_topic(a).is_err() || validate_subscribe_topic(b).is_err() {
+        return false;
+    }
+
+    // a filter starting with a wildcard matches no topic beginning with '$'
+    if (a.starts_with('$') && b.starts_with(['+', '#']))
+        || (b.starts_with('$') && a.starts_with(['+', '#']))
+    {
+        return false;
+    }
+
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (None, None) => return true,
+            // `foo/#` also matches the parent level `foo`
+            (None, Some(_)) => return b_levels.next().is_none() && b.ends_with("/#"),
+            (Some(_), None) => return a_levels.next().is_none() && a.ends_with("/#"),
+            (Some(x), Some(y)) if x == "+" || y == "+" || x == y => {}
+            _ => return false,
+        }
+    }
+}
+
+fn is_wildcard_filter(filter: &str) -> bool {
+    filter.contains(['+', '#'])
+}
+
+// specificity ranks the levels of a filter for best_match, the end of the
+// filter ranks above any level so that `foo` is preferred over `foo/#`.
+fn specificity(filter: &str) -> Vec<u8> {
+    filter
+        .split('/')
+        .map(|level| match level {
+            "#" => 0,
+            "+" => 1,
+            _ => 2,
+        })
+        .chain([3])
+        .collect()
+}
+
+fn is_shared_filter(filter: &str) -> bool {
+    filter.starts_with("$share/")
+}
+
+// Up to LINEAR_MATCH_THRESHOLD wildcard filters are matched one after the
+// other, beyond it the trie is cheaper than scanning the list.
+const LINEAR_MATCH_THRESHOLD: usize = 16;
+
+// TopicMatcher keeps wildcard-free filters in a hash set so that they are
+// matched with a single lookup. The filters containing '+' or '#' are kept in
+// a list while there are only a few of them and moved to the trie once their
+// number goes above LINEAR_MATCH_THRESHOLD, and back to the list when it drops
+// to the threshold again. The number of subscriptions can be limited, the
+// count is kept up to date on subscribe and unsubscribe.
 pub struct TopicMatcher {
-    trie: RwLock<Rc<Trie>>,
+    trie: RwLock<Rc<Trie<()>>>,
+    exact: RwLock<HashSet<String>>,
+    linear: RwLock<Vec<String>>,
+    count: AtomicUsize,
+    wildcards: AtomicUsize,
+    max_subscriptions: Option<usize>,
 }
 
 impl TopicMatcher {
     pub fn new() -> Self {
         Self {
             trie: RwLock::new(Rc::new(Trie::new())),
+            exact: RwLock::new(HashSet::new()),
+            linear: RwLock::new(Vec::new()),
+            count: AtomicUsize::new(0),
+            wildcards: AtomicUsize::new(0),
+            max_subscriptions: None,
+        }
+    }
+
+    /// Creates a matcher that holds at most `max` subscriptions, subscribing
+    /// to a new filter beyond it fails with `TooManySubscriptions`.
+    pub fn with_max_subscriptions(max: usize) -> Self {
+        Self {
+            max_subscriptions: Some(max),
+            ..Self::new()
         }
     }
 
     pub fn subscribe(&self, topic: &str) -> Result<(), SubscribeTopicValidationError> {
         let trie = self.trie.write().unwrap();
-        let result = validate_subscribe_topic(topic);
-        match result {
-            Ok(_v) => {
-                trie.insert(topic);
-                Ok(())
+        let mut exact = self.exact.write().unwrap();
+        let mut linear = self.linear.write().unwrap();
+        validate_subscribe_topic(topic)?;
+
+        let wildcard = is_wildcard_filter(topic);
+        let subscribed = if wildcard {
+            linear.iter().any(|f| f == topic) || trie.is_subscribed(topic)
+        } else {
+            exact.contains(topic)
+        };
+        if subscribed {
+            return Ok(());
+        }
+        if let Some(max) = self.max_subscriptions {
+            if self.count.load(Ordering::Relaxed) >= max {
+                return Err(SubscribeTopicValidationError::TooManySubscriptions(max));
             }
-            Err(e) => Err(e),
         }
+
+        if !wildcard {
+            exact.insert(topic.to_string());
+        } else if self.wildcards.fetch_add(1, Ordering::Relaxed) < LINEAR_MATCH_THRESHOLD {
+            linear.push(topic.to_string());
+        } else {
+            // the list is only non-empty when crossing the threshold
+            for filter in linear.drain(..) {
+                trie.insert(&filter, ());
+            }
+            trie.insert(topic, ());
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Ok(())
     }
 
     pub fn unsubscribe(&self, topic: &str) {
         let trie = self.trie.write().unwrap();
-        trie.delete(topic)
+        let mut exact = self.exact.write().unwrap();
+        let mut linear = self.linear.write().unwrap();
+        if !is_wildcard_filter(topic) {
+            if exact.remove(topic) {
+                self.count.fetch_sub(1, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        let deleted = match linear.iter().position(|f| f == topic) {
+            Some(pos) => {
+                linear.swap_remove(pos);
+                true
+            }
+            None => trie.delete(topic),
+        };
+        if !deleted {
+            return;
+        }
+        self.count.fetch_sub(1, Ordering::Relaxed);
+        if self.wildcards.fetch_sub(1, Ordering::Relaxed) == LINEAR_MATCH_THRESHOLD + 1 {
+            *linear = trie.subscriptions();
+            trie.clear_subscriptions();
+        }
+    }
+
+    /// Removes all the subscriptions, retained messages held by the trie are
+    /// kept.
+    pub fn clear_subscriptions(&self) {
+        let trie = self.trie.write().unwrap();
+        let mut exact = self.exact.write().unwrap();
+        let mut linear = self.linear.write().unwrap();
+        trie.clear_subscriptions();
+        exact.clear();
+        linear.clear();
+        self.count.store(0, Ordering::Relaxed);
+        self.wildcards.store(0, Ordering::Relaxed);
     }
 
     pub fn match_topic(&self, topic: &str) -> bool {
         let trie = self.trie.read().unwrap();
-        return trie.contains(topic);
+        let exact = self.exact.read().unwrap();
+        let linear = self.linear.read().unwrap();
+        return exact.contains(topic)
+            || linear.iter().any(|f| levels_match(f, topic))
+            || trie.contains(topic);
+    }
+
+    /// Returns the most specific filter matching a topic name. An exact
+    /// match is the most specific, the wildcard filters are compared level
+    /// by level from the first one: a literal level is preferred over '+'
+    /// and '+' over '#', so `foo/bar/#` wins over `foo/+` for `foo/bar`.
+    pub fn best_match(&self, topic: &str) -> Option<String> {
+        let trie = self.trie.read().unwrap();
+        let exact = self.exact.read().unwrap();
+        let linear = self.linear.read().unwrap();
+        if exact.contains(topic) {
+            return Some(topic.to_string());
+        }
+        linear
+            .iter()
+            .filter(|f| levels_match(f, topic))
+            .cloned()
+            .chain(trie.best_match(topic))
+            .max_by_key(|f| specificity(f))
+    }
+
+    pub fn number_of_subscriptions(&self) -> usize {
+        let trie = self.trie.read().unwrap();
+        let exact = self.exact.read().unwrap();
+        let linear = self.linear.read().unwrap();
+        exact.len() + linear.len() + trie.number_of_entries()
     }
 
-    pub fn number_of_subscriptions(&self) {
+    /// Returns the sorted filters containing '+' or '#', shared
+    /// subscriptions ($share/...) are listed here as well.
+    pub fn wildcard_subscriptions(&self) -> Vec<String> {
         let trie = self.trie.read().unwrap();
-        trie.number_of_entries();
+        let exact = self.exact.read().unwrap();
+        let linear = self.linear.read().unwrap();
+        let mut filters: Vec<String> = exact
+            .iter()
+            .filter(|f| is_shared_filter(f))
+            .chain(linear.iter())
+            .cloned()
+            .chain(trie.subscriptions())
+            .collect();
+        filters.sort();
+        filters
+    }
+
+    /// Returns the sorted filters without wildcards, excluding shared
+    /// subscriptions.
+    pub fn exact_subscriptions(&self) -> Vec<String> {
+        let exact = self.exact.read().unwrap();
+        let mut filters: Vec<String> = exact
+            .iter()
+            .filter(|f| !is_shared_filter(f))
+            .cloned()
+            .collect();
+        filters.sort();
+        filters
     }
 
     pub fn print_subscriptions(&self) {
         let trie = self.trie.read().unwrap();
+        let exact = self.exact.read().unwrap();
+        let linear = self.linear.read().unwrap();
+        for v in exact.iter().chain(linear.iter()) {
+            println!("{}", v);
+        }
         trie.print_entries();
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::errors::{Error, PublishTopicValidationError, SubscribeTopicValidationError};
+    use crate::trie::Trie;
+
+    use super::canonicalize_filter;
+    use super::dedup_topic_filters;
+    use super::filters_overlap;
+    use super::topic_matches_filter;
     use super::validate_publish_topic;
+    use super::validate_publish_topics;
     use super::validate_subscribe_topic;
+    use super::validate_subscribe_topics;
     use super::TopicMatcher;
+    use super::LINEAR_MATCH_THRESHOLD;
 
     #[test]
     fn test_basic() {
@@ -103,6 +441,51 @@ mod tests {
         assert!(validated.is_ok(), "{}", validated.unwrap_err());
     }
 
+    #[test]
+    fn test_canonicalize_filter() {
+        let filters = [
+            ("foo/bar", "foo/bar"),
+            ("foo//bar", "foo//bar"),
+            ("/foo/bar", "/foo/bar"),
+            ("foo/bar/", "foo/bar/"),
+            ("//", "//"),
+            ("Foo/ bar", "Foo/ bar"),
+            ("+/+/#", "+/+/#"),
+        ];
+        for f in filters {
+            assert_eq!(canonicalize_filter(f.0), f.1);
+        }
+
+        let matcher = TopicMatcher::new();
+        assert!(matcher.subscribe(&canonicalize_filter("foo//bar")).is_ok());
+        assert!(matcher.match_topic("foo//bar"));
+        assert!(!matcher.match_topic("foo/bar"));
+    }
+
+    #[test]
+    fn test_dedup_topic_filters() {
+        let filters = || {
+            vec![
+                ("a/b".to_string(), 0u8),
+                ("c/+".to_string(), 1u8),
+                ("a/b".to_string(), 2u8),
+            ]
+        };
+
+        let result = dedup_topic_filters(filters(), false);
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        assert_eq!(
+            result.unwrap(),
+            vec![("a/b".to_string(), 2u8), ("c/+".to_string(), 1u8)]
+        );
+
+        let result = dedup_topic_filters(filters(), true);
+        assert_eq!(
+            result.unwrap_err(),
+            Error::DuplicateTopicFilter("a/b".to_string())
+        );
+    }
+
     #[test]
     fn test_publish_topic_validation() {
         let valid_publish_topics = ["pub/topic", "pub//topic", "pub/ /topic"];
@@ -130,6 +513,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_publish_topics_validation() {
+        let result = validate_publish_topics(&["a/b", "pub//topic", "c"]);
+        assert!(result.is_ok(), "{:?}", result.unwrap_err());
+        assert!(validate_publish_topics(&[]).is_ok());
+
+        let result = validate_publish_topics(&["a/b", "c/d", "pub/+/topic", "e/#"]);
+        assert!(matches!(
+            result,
+            Err((2, PublishTopicValidationError::InvalidTopic { ch: '+', .. }))
+        ));
+    }
+
+    #[test]
+    fn test_subscribe_topics_validation() {
+        let results = validate_subscribe_topics(&[
+            "a/b",
+            "",
+            "a/+",
+            "a/b#",
+            "$share/group/a/#",
+            "$share/+/a",
+            "$share//a",
+            "$share/group",
+            "$share/group/",
+            "$SYS/#",
+        ]);
+        assert_eq!(results.len(), 10);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(SubscribeTopicValidationError::EmptySubscriptionTopic)
+        ));
+        assert!(results[2].is_ok());
+        assert!(matches!(
+            results[3],
+            Err(SubscribeTopicValidationError::InvalidTopic { ch: '#', .. })
+        ));
+        assert!(results[4].is_ok());
+        for result in &results[5..9] {
+            assert!(matches!(
+                result,
+                Err(SubscribeTopicValidationError::InvalidSharedSubscription(_))
+            ));
+        }
+        assert!(results[9].is_ok());
+        assert!(validate_subscribe_topics(&[]).is_empty());
+    }
+
     #[test]
     fn test_subscribe_topic_validation() {
         let valid_subscribe_topics = [
@@ -174,28 +606,73 @@ mod tests {
             let result = validate_subscribe_topic(t);
             assert!(result.is_err(), "Invalid topic '{}' is validated.", t,);
         }
+        let result = validate_subscribe_topic("sub/+topic");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "invalid topic - subscribe topic 'sub/+topic' cannot contain the character '+'"
+        );
+        let result = validate_publish_topic("pub/topic/#");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "invalid topic - publish topic 'pub/topic/#' cannot contain the character '#'"
+        );
     }
 
+    const VALID_SUBSCRIBE_TOPIC_MATCHES: [(&str, &str); 15] = [
+        ("foo/#", "foo"),
+        ("foo//bar", "foo//bar"),
+        ("foo//+", "foo//bar"),
+        ("foo/+/+/baz", "foo///baz"),
+        ("foo/bar/+", "foo/bar/"),
+        ("foo/bar", "foo/bar"),
+        ("foo/+", "foo/bar"),
+        ("foo/+/baz", "foo/bar/baz"),
+        ("A/B/+/#", "A/B/B/C"),
+        ("foo/+/#", "foo/bar"),
+        ("#", "foo/bar/baz"),
+        ("/#", "/foo/bar"),
+        ("foo/+/#", "foo/bar/baz"),
+        ("#", "foo/bar/baz"),
+        ("/#", "/foo/bar"),
+    ];
+
+    const VALID_SUBSCRIBE_TOPIC_NO_MATCHES: [(&str, &str); 31] = [
+        ("test/6/#", "test/3"),
+        ("test/6/#", "test/3"),
+        ("test/6/#", "test/3"),
+        ("test/6/#", "test/^^3"),
+        ("foo/bar", "foo"),
+        ("foo/+", "foo/bar/baz"),
+        ("foo/+/baz", "foo/bar/bar"),
+        ("foo/+/#", "fo2/bar/baz"),
+        ("/#", "foo/bar"),
+        ("+foo", "+foo"),
+        ("fo+o", "fo+o"),
+        ("foo+", "foo+"),
+        ("+foo/bar", "+foo/bar"),
+        ("foo+/bar", "foo+/bar"),
+        ("foo/+bar", "foo/+bar"),
+        ("foo/bar+", "foo/bar+"),
+        ("+foo", "afoo"),
+        ("fo+o", "foao"),
+        ("foo+", "fooa"),
+        ("+foo/bar", "afoo/bar"),
+        ("foo+/bar", "fooa/bar"),
+        ("foo/+bar", "foo/abar"),
+        ("foo/bar+", "foo/bara"),
+        ("#foo", "#foo"),
+        ("fo#o", "fo#o"),
+        ("foo#", "foo#"),
+        ("#foo/bar", "#foo/bar"),
+        ("foo#/bar", "foo#/bar"),
+        ("foo/#bar", "foo/#bar"),
+        ("foo/bar#", "foo/bar#"),
+        ("foo+", "fooa"),
+    ];
+
     #[test]
     fn test_subscribe_valid_topic_match() {
-        let valid_subscribe_topic_matches = [
-            ("foo/#", "foo"),
-            ("foo//bar", "foo//bar"),
-            ("foo//+", "foo//bar"),
-            ("foo/+/+/baz", "foo///baz"),
-            ("foo/bar/+", "foo/bar/"),
-            ("foo/bar", "foo/bar"),
-            ("foo/+", "foo/bar"),
-            ("foo/+/baz", "foo/bar/baz"),
-            ("A/B/+/#", "A/B/B/C"),
-            ("foo/+/#", "foo/bar"),
-            ("#", "foo/bar/baz"),
-            ("/#", "/foo/bar"),
-            ("foo/+/#", "foo/bar/baz"),
-            ("#", "foo/bar/baz"),
-            ("/#", "/foo/bar"),
-        ];
-        for t in valid_subscribe_topic_matches {
+        for t in VALID_SUBSCRIBE_TOPIC_MATCHES {
             let matcher = TopicMatcher::new();
             let result = matcher.subscribe(t.0);
             assert!(
@@ -215,40 +692,7 @@ mod tests {
 
     #[test]
     fn test_subscribe_valid_topic_no_match() {
-        let valid_subscribe_topic_no_matches = [
-            ("test/6/#", "test/3"),
-            ("test/6/#", "test/3"),
-            ("test/6/#", "test/3"),
-            ("test/6/#", "test/^^3"),
-            ("foo/bar", "foo"),
-            ("foo/+", "foo/bar/baz"),
-            ("foo/+/baz", "foo/bar/bar"),
-            ("foo/+/#", "fo2/bar/baz"),
-            ("/#", "foo/bar"),
-            ("+foo", "+foo"),
-            ("fo+o", "fo+o"),
-            ("foo+", "foo+"),
-            ("+foo/bar", "+foo/bar"),
-            ("foo+/bar", "foo+/bar"),
-            ("foo/+bar", "foo/+bar"),
-            ("foo/bar+", "foo/bar+"),
-            ("+foo", "afoo"),
-            ("fo+o", "foao"),
-            ("foo+", "fooa"),
-            ("+foo/bar", "afoo/bar"),
-            ("foo+/bar", "fooa/bar"),
-            ("foo/+bar", "foo/abar"),
-            ("foo/bar+", "foo/bara"),
-            ("#foo", "#foo"),
-            ("fo#o", "fo#o"),
-            ("foo#", "foo#"),
-            ("#foo/bar", "#foo/bar"),
-            ("foo#/bar", "foo#/bar"),
-            ("foo/#bar", "foo/#bar"),
-            ("foo/bar#", "foo/bar#"),
-            ("foo+", "fooa"),
-        ];
-        for t in valid_subscribe_topic_no_matches {
+        for t in VALID_SUBSCRIBE_TOPIC_NO_MATCHES {
             let matcher = TopicMatcher::new();
             _ = matcher.subscribe(t.0);
             assert!(
@@ -259,4 +703,374 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_max_subscriptions() {
+        let matcher = TopicMatcher::with_max_subscriptions(2);
+        assert!(matcher.subscribe("a/b").is_ok());
+        assert!(matcher.subscribe("a/+").is_ok());
+        assert!(matches!(
+            matcher.subscribe("c/#"),
+            Err(SubscribeTopicValidationError::TooManySubscriptions(2))
+        ));
+        assert!(matches!(
+            matcher.subscribe("c/d"),
+            Err(SubscribeTopicValidationError::TooManySubscriptions(2))
+        ));
+        // subscribing again to an existing filter does not add one
+        assert!(matcher.subscribe("a/b").is_ok());
+        assert!(matcher.subscribe("a/+").is_ok());
+        assert!(!matcher.match_topic("c/d"));
+
+        matcher.unsubscribe("a/+");
+        // unsubscribing from an unknown filter does not free a slot
+        matcher.unsubscribe("x/+");
+        assert!(matcher.subscribe("c/#").is_ok());
+        assert!(matcher.match_topic("c/d"));
+        assert!(matcher.subscribe("c/d").is_err());
+
+        matcher.unsubscribe("a/b");
+        assert!(matcher.subscribe("c/d").is_ok());
+        assert_eq!(matcher.number_of_subscriptions(), 2);
+    }
+
+    #[test]
+    fn test_topic_matches_filter() {
+        for t in VALID_SUBSCRIBE_TOPIC_MATCHES {
+            assert!(
+                topic_matches_filter(t.0, t.1),
+                "Matching of topic '{}' with '{}' failed, should match",
+                t.1,
+                t.0,
+            );
+        }
+        for t in VALID_SUBSCRIBE_TOPIC_NO_MATCHES {
+            assert!(
+                !topic_matches_filter(t.0, t.1),
+                "Matching of topic '{}' with '{}' failed, should not match",
+                t.1,
+                t.0,
+            );
+        }
+
+        let dollar_topics = [
+            ("#", "$SYS/broker/uptime", false),
+            ("+/broker/uptime", "$SYS/broker/uptime", false),
+            ("$SYS/#", "$SYS/broker/uptime", true),
+            ("$SYS/+/uptime", "$SYS/broker/uptime", true),
+            ("a/#", "a/$b", true),
+        ];
+        for t in dollar_topics {
+            assert_eq!(topic_matches_filter(t.0, t.1), t.2, "{} {}", t.0, t.1);
+        }
+
+        // trailing and empty levels
+        assert!(topic_matches_filter("foo/+", "foo/"));
+        assert!(!topic_matches_filter("foo/+", "foo"));
+        assert!(!topic_matches_filter("foo/", "foo"));
+        assert!(!topic_matches_filter("foo", "foo/"));
+        assert!(topic_matches_filter("foo/#", "foo/"));
+        assert!(topic_matches_filter("+", ""));
+        assert!(!topic_matches_filter("a/+", "a/+"));
+    }
+
+    #[test]
+    fn test_filters_overlap() {
+        let overlapping = [
+            ("a/b", "a/b"),
+            ("a/+", "a/b"),
+            ("a/+", "+/b"),
+            ("a/#", "a/b/c"),
+            ("a/#", "a"),
+            ("#", "a/b"),
+            ("+/+", "a/#"),
+            ("a/+/c", "a/b/+"),
+            ("$SYS/#", "$SYS/+"),
+            ("+", "+/#"),
+        ];
+        for t in overlapping {
+            assert!(
+                filters_overlap(t.0, t.1),
+                "'{}' and '{}' should overlap",
+                t.0,
+                t.1
+            );
+            assert!(
+                filters_overlap(t.1, t.0),
+                "'{}' and '{}' should overlap",
+                t.1,
+                t.0
+            );
+        }
+
+        let disjoint = [
+            ("a/b", "a/c"),
+            ("a/+", "a/b/c"),
+            ("a/+", "a"),
+            ("a/b/#", "a/c/#"),
+            ("+/b", "a/c"),
+            ("a", "a/"),
+            ("#", "$SYS/uptime"),
+            ("+/uptime", "$SYS/uptime"),
+            ("a/+", "a/b/+"),
+            ("a/b/#", "a"),
+            ("a/+", "a/#/b"),
+        ];
+        for t in disjoint {
+            assert!(
+                !filters_overlap(t.0, t.1),
+                "'{}' and '{}' should not overlap",
+                t.0,
+                t.1
+            );
+            assert!(
+                !filters_overlap(t.1, t.0),
+                "'{}' and '{}' should not overlap",
+                t.1,
+                t.0
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_topic_levels() {
+        for topic in ["a//b", "/a", "a/", "//", "/"] {
+            let validated = validate_publish_topic(topic);
+            assert!(validated.is_ok(), "{}", validated.unwrap_err());
+            let validated = validate_subscribe_topic(topic);
+            assert!(validated.is_ok(), "{}", validated.unwrap_err());
+        }
+
+        // '+' matches an empty level, an exact level does not
+        let matches = [
+            ("a/+/b", "a//b", true),
+            ("a/b", "a//b", false),
+            ("a//b", "a//b", true),
+            ("a//b", "a/b", false),
+            ("+/+", "/", true),
+            ("+//+", "a//b", true),
+            ("a/+/+", "a//", true),
+            ("a/#", "a//b", true),
+        ];
+        for t in matches {
+            assert_eq!(topic_matches_filter(t.0, t.1), t.2, "{} {}", t.0, t.1);
+
+            let matcher = TopicMatcher::new();
+            assert!(matcher.subscribe(t.0).is_ok());
+            assert_eq!(matcher.match_topic(t.1), t.2, "{} {}", t.0, t.1);
+        }
+    }
+
+    #[test]
+    fn test_wildcard_and_exact_subscriptions() {
+        let matcher = TopicMatcher::new();
+        let filters = [
+            "a/b",
+            "a/+",
+            "#",
+            "/",
+            "$share/group/a/b",
+            "$share/group/+/b",
+            "$SYS/uptime",
+            "a/b/#",
+        ];
+        for f in filters {
+            assert!(matcher.subscribe(f).is_ok());
+        }
+        assert_eq!(matcher.exact_subscriptions(), ["$SYS/uptime", "/", "a/b"]);
+        assert_eq!(
+            matcher.wildcard_subscriptions(),
+            ["#", "$share/group/+/b", "$share/group/a/b", "a/+", "a/b/#"]
+        );
+
+        // wildcard filters held by the trie are listed as well
+        let matcher = TopicMatcher::new();
+        let mut expected: Vec<String> = (0..LINEAR_MATCH_THRESHOLD + 1)
+            .map(|i| format!("a/{}/#", i))
+            .collect();
+        for f in expected.iter() {
+            assert!(matcher.subscribe(f).is_ok());
+        }
+        expected.sort();
+        assert_eq!(matcher.wildcard_subscriptions(), expected);
+        assert!(matcher.exact_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_best_match() {
+        let filters = ["foo/#", "foo/+", "+/bar", "#", "a/+/c", "a/b/#"];
+        let topics = [
+            ("foo/bar", Some("foo/bar")),
+            ("foo/baz", Some("foo/+")),
+            ("foo", Some("foo/#")),
+            ("x/bar", Some("+/bar")),
+            ("a/b/c", Some("a/b/#")),
+            ("a/x/c", Some("a/+/c")),
+            ("x", Some("#")),
+        ];
+
+        // the wildcard filters matched linearly and by the trie
+        let linear = TopicMatcher::new();
+        let trie = TopicMatcher::new();
+        for i in 0..LINEAR_MATCH_THRESHOLD {
+            assert!(trie.subscribe(&format!("z/{}/+", i)).is_ok());
+        }
+        for matcher in [linear, trie] {
+            assert_eq!(matcher.best_match("foo/bar"), None);
+            for f in filters {
+                assert!(matcher.subscribe(f).is_ok());
+            }
+            assert_eq!(matcher.best_match("foo/bar").as_deref(), Some("foo/+"));
+            assert!(matcher.subscribe("foo/bar").is_ok());
+            for t in topics {
+                assert_eq!(matcher.best_match(t.0).as_deref(), t.1, "{}", t.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_clear_subscriptions() {
+        let matcher = TopicMatcher::with_max_subscriptions(2);
+        assert!(matcher.subscribe("a/b").is_ok());
+        assert!(matcher.subscribe("a/+").is_ok());
+        matcher
+            .trie
+            .read()
+            .unwrap()
+            .set_retained("a/c", b"retained");
+
+        matcher.clear_subscriptions();
+        assert_eq!(matcher.number_of_subscriptions(), 0);
+        assert!(!matcher.match_topic("a/b"));
+        assert!(!matcher.match_topic("a/c"));
+        assert_eq!(
+            matcher.trie.read().unwrap().get_retained("a/c"),
+            Some(b"retained".to_vec())
+        );
+
+        // the limit counts from zero again
+        assert!(matcher.subscribe("x").is_ok());
+        assert!(matcher.subscribe("y/#").is_ok());
+        assert!(matcher.subscribe("z").is_err());
+    }
+
+    #[test]
+    fn test_linear_threshold() {
+        let filters: Vec<String> = (0..LINEAR_MATCH_THRESHOLD + 4)
+            .map(|i| {
+                if i % 2 == 0 {
+                    format!("a/{}/+", i)
+                } else {
+                    format!("+/{}/#", i)
+                }
+            })
+            .collect();
+        let topics: Vec<String> = (0..LINEAR_MATCH_THRESHOLD + 5)
+            .flat_map(|i| [format!("a/{}/x", i), format!("b/{}", i), format!("a/{}", i)])
+            .collect();
+
+        let matcher = TopicMatcher::new();
+        assert!(matcher.subscribe("a/b").is_ok());
+        let trie = Trie::new();
+        trie.insert("a/b", ());
+        let check = |matcher: &TopicMatcher, trie: &Trie<()>, n: usize| {
+            let in_trie = matcher.trie.read().unwrap().number_of_entries();
+            if n <= LINEAR_MATCH_THRESHOLD {
+                assert_eq!(in_trie, 0);
+            } else {
+                assert_eq!(in_trie, n);
+            }
+            assert_eq!(matcher.number_of_subscriptions(), n + 1);
+            for t in topics.iter() {
+                assert_eq!(matcher.match_topic(t), trie.contains(t), "{} {}", t, n);
+            }
+        };
+
+        for (n, f) in filters.iter().enumerate() {
+            assert!(matcher.subscribe(f).is_ok());
+            trie.insert(f, ());
+            check(&matcher, &trie, n + 1);
+        }
+        for (n, f) in filters.iter().enumerate().rev() {
+            matcher.unsubscribe(f);
+            trie.delete(f);
+            check(&matcher, &trie, n);
+        }
+    }
+
+    #[test]
+    fn test_exact_and_wildcard_match_as_trie() {
+        let filters = [
+            "foo/bar",
+            "foo//bar",
+            "foo/bar/baz",
+            "/foo",
+            "foo/",
+            "foo",
+            "foo/+",
+            "foo/+/baz",
+            "+/bar/#",
+            "sport/#",
+            "#",
+            "a/b/c/d",
+            "a/+/+/d",
+        ];
+        let topics = [
+            "foo",
+            "foo/",
+            "foo/bar",
+            "foo//bar",
+            "foo/bar/baz",
+            "foo/baz/baz",
+            "/foo",
+            "/foo/",
+            "x/bar",
+            "x/bar/y",
+            "sport",
+            "sport/tennis",
+            "a/b/c/d",
+            "a/x/y/d",
+            "a/b/c",
+            "b/c/d",
+        ];
+
+        // every subset of the filters built from a sliding window, the
+        // complete set is matched as well
+        for n in 1..=filters.len() {
+            for window in filters.windows(n) {
+                let matcher = TopicMatcher::new();
+                let trie = Trie::new();
+                for f in window {
+                    assert!(matcher.subscribe(f).is_ok());
+                    trie.insert(f, ());
+                }
+                assert_eq!(matcher.number_of_subscriptions(), trie.number_of_entries());
+                for t in topics {
+                    assert_eq!(
+                        matcher.match_topic(t),
+                        trie.contains(t),
+                        "Matching of topic '{}' against {:?} differs from the trie",
+                        t,
+                        window
+                    );
+                }
+
+                // unsubscribe every other filter of the window
+                for f in window.iter().step_by(2) {
+                    matcher.unsubscribe(f);
+                    trie.delete(f);
+                }
+                assert_eq!(matcher.number_of_subscriptions(), trie.number_of_entries());
+                for t in topics {
+                    assert_eq!(
+                        matcher.match_topic(t),
+                        trie.contains(t),
+                        "Matching of topic '{}' against {:?} differs from the trie after unsubscribing",
+                        t,
+                        window
+                    );
+                }
+            }
+        }
+    }
 }
@@ -1,16 +1,16 @@
 use std::{
     borrow::{Borrow, BorrowMut},
     cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     iter::Peekable,
     rc::{Rc, Weak},
     str::Split,
 };
 
-type RcTrieNode = Rc<TrieNode>;
+type RcTrieNode<T> = Rc<TrieNode<T>>;
 type TrieStack = Vec<Vec<String>>;
 
-fn print_trie_nodes(node: &RcTrieNode) -> TrieStack {
+fn print_trie_nodes<T>(node: &RcTrieNode<T>) -> TrieStack {
     let mut stack: TrieStack = Vec::new();
     if !node.as_ref().borrow().children.borrow().is_empty() {
         stack.push(Vec::new());
@@ -19,13 +19,16 @@ fn print_trie_nodes(node: &RcTrieNode) -> TrieStack {
     return stack;
 }
 
-fn print_trie_node(node: &RcTrieNode, stack: &mut TrieStack) {
+fn print_trie_node<T>(node: &RcTrieNode<T>, stack: &mut TrieStack) {
     let current = stack.pop().unwrap();
 
     let borrowed_node = node.as_ref().borrow();
     let borrowed_hash_map = borrowed_node.children.borrow();
     if borrowed_hash_map.is_empty() {
-        stack.push(current);
+        // leaf nodes that only hold a retained message are not entries
+        if node.has_subscription() {
+            stack.push(current);
+        }
     } else {
         for (_k, v) in &*borrowed_hash_map {
             let mut child_list = current.clone();
@@ -44,24 +47,24 @@ fn print_trie_node(node: &RcTrieNode, stack: &mut TrieStack) {
     }
 }
 
-fn match_topic_part(
-    node: &RcTrieNode,
-    parts: &mut Peekable<Split<&str>>,
+fn match_topic_part<T>(
+    node: &RcTrieNode<T>,
+    parts: &mut Peekable<Split<char>>,
     current: Option<&str>,
 ) -> bool {
-    fn match_child(node: &RcTrieNode, parts: &mut Peekable<Split<&str>>, value: &str) -> bool {
+    fn match_child<T>(node: &RcTrieNode<T>, parts: &Peekable<Split<char>>, value: &str) -> bool {
         let child = node.get_child(value);
         match child {
             Some(v) => {
-                // found +, check it is the last part
-                // from MQTTv5 spec
+                // each branch consumes its own copy of the remaining parts, so
+                // that a failed '+' branch does not skip parts for the exact one
+                let mut parts = parts.clone();
+                // found +, the child must hold a subscription once the parts are
+                // consumed, from MQTTv5 spec
                 // e.g “sport/tennis/+” matches “sport/tennis/player1” and
                 // “sport/tennis/player2”, but not “sport/tennis/player1/ranking”.
-                if value == "+" && parts.peek().is_none() {
-                    return true;
-                }
                 let next = parts.next();
-                match_topic_part(&v, parts, next)
+                match_topic_part(&v, &mut parts, next)
             }
             _ => false,
         }
@@ -96,48 +99,57 @@ fn match_topic_part(
     return false;
 }
 
-fn match_topic(node: &RcTrieNode, topic: &str) -> bool {
-    let mut peekable = topic.split("/").peekable();
+fn match_topic<T>(node: &RcTrieNode<T>, topic: &str, separator: char) -> bool {
+    let mut peekable = topic.split(separator).peekable();
     let parts = peekable.borrow_mut();
 
     let part = parts.next();
     return match_topic_part(node, parts, part);
 }
 
+// TrieNode holds the level name in value, the data of the subscription to the
+// filter ending at the node, if any, and the retained message of the topic
+// name ending at it.
 #[derive(Debug)]
-struct TrieNode {
-    value: Option<String>,
-    parent: RefCell<Weak<TrieNode>>,
-    children: RefCell<HashMap<String, RcTrieNode>>,
-    subscribed: RefCell<bool>,
+struct TrieNode<T> {
+    value: Option<Rc<str>>,
+    parent: RefCell<Weak<TrieNode<T>>>,
+    children: RefCell<HashMap<Rc<str>, RcTrieNode<T>>>,
+    subscription: RefCell<Option<T>>,
+    retained: RefCell<Option<Vec<u8>>>,
 }
 
-fn new_parent(parent: Option<Rc<TrieNode>>) -> RefCell<Weak<TrieNode>> {
+fn new_parent<T>(parent: Option<Rc<TrieNode<T>>>) -> RefCell<Weak<TrieNode<T>>> {
     match parent {
         Some(v) => RefCell::new(Rc::downgrade(&v)),
         _ => RefCell::new(Weak::new()),
     }
 }
 
-impl TrieNode {
-    fn new(value: Option<String>, parent: Option<Rc<TrieNode>>, subscribed: bool) -> RcTrieNode {
+impl<T> TrieNode<T> {
+    fn new(value: Option<Rc<str>>, parent: Option<Rc<TrieNode<T>>>) -> RcTrieNode<T> {
         return Rc::new(Self {
             value: value,
             parent: new_parent(parent),
             children: RefCell::new(HashMap::new()),
-            subscribed: RefCell::new(subscribed),
+            subscription: RefCell::new(None),
+            retained: RefCell::new(None),
         });
     }
 
     fn has_subscription(&self) -> bool {
-        return *self.subscribed.borrow();
+        return self.subscription.borrow().is_some();
     }
 
-    fn set_subscription(&self, subscribed: bool) {
-        *self.subscribed.borrow_mut() = subscribed;
+    fn set_subscription(&self, subscription: Option<T>) -> Option<T> {
+        self.subscription.replace(subscription)
     }
 
-    fn get_parent(&self) -> Option<Rc<TrieNode>> {
+    fn has_retained(&self) -> bool {
+        self.retained.borrow().is_some()
+    }
+
+    fn get_parent(&self) -> Option<Rc<TrieNode<T>>> {
         self.parent.borrow().upgrade()
     }
 
@@ -149,117 +161,818 @@ impl TrieNode {
         self.children.borrow().contains_key(part)
     }
 
-    fn get_child(&self, part: &str) -> Option<RcTrieNode> {
+    fn get_child(&self, part: &str) -> Option<RcTrieNode<T>> {
         match self.children.borrow().get(part) {
             Some(v) => Some(v.clone()),
             _ => None,
         }
     }
 
-    fn get_or_insert_child(&self, part: &str, parent: RcTrieNode, subscribed: bool) -> RcTrieNode {
-        let mut map = self.children.borrow_mut();
-        return map
-            .entry(part.to_string())
-            .or_insert_with(|| TrieNode::new(Some(part.to_string()), Some(parent), subscribed))
-            .clone();
+    fn get_or_insert_child(
+        &self,
+        part: &str,
+        parent: RcTrieNode<T>,
+        interner: &Interner,
+    ) -> RcTrieNode<T> {
+        if let Some(child) = self.get_child(part) {
+            return child;
+        }
+        let key = interner.intern(part);
+        let child = TrieNode::new(Some(key.clone()), Some(parent));
+        self.children.borrow_mut().insert(key, child.clone());
+        child
     }
 
-    fn remove_child(&self, key: &String) {
+    fn remove_child(&self, key: &str) {
         self.children.borrow_mut().remove(key);
     }
 }
 
-pub struct Trie {
-    root: RcTrieNode,
+// Interner hands out shared copies of the level names so that nodes with the
+// same name, e.g. "status" under every device, reference one allocation. When
+// interning is disabled every node gets its own copy. A name is dropped from
+// the interner when the last node using it is deleted.
+struct Interner {
+    levels: Option<RefCell<HashSet<Rc<str>>>>,
+}
+
+impl Interner {
+    fn intern(&self, part: &str) -> Rc<str> {
+        let levels = match &self.levels {
+            Some(v) => v,
+            None => return Rc::from(part),
+        };
+        let mut levels = levels.borrow_mut();
+        if let Some(v) = levels.get(part) {
+            return v.clone();
+        }
+        let v: Rc<str> = Rc::from(part);
+        levels.insert(v.clone());
+        v
+    }
+
+    // release drops a name from the interner when key, taken from a node that
+    // was removed and dropped, is its last use outside the interner
+    fn release(&self, key: Rc<str>) {
+        if let Some(levels) = &self.levels {
+            if Rc::strong_count(&key) == 2 {
+                levels.borrow_mut().remove(&key);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        match &self.levels {
+            Some(v) => v.borrow().len(),
+            None => 0,
+        }
+    }
+}
+
+// prune_node removes a node that no longer holds a subscription or a retained
+// message, together with its ancestors that are left without a subscription, a
+// retained message or children. Nodes still in use are kept.
+fn prune_node<T>(node: RcTrieNode<T>, interner: &Interner) {
+    let mut current = node;
+    while !current.has_subscription() && !current.has_retained() && !current.has_children() {
+        let parent = match current.get_parent() {
+            Some(v) => v,
+            None => return,
+        };
+        let key = current.value.clone().unwrap();
+        parent.remove_child(&key);
+        // the removed node is dropped before its name is released
+        current = parent;
+        interner.release(key);
+    }
+}
+
+// TrieStats breaks the subscribed filters down by the wildcards they contain,
+// a filter with both '+' and '#' is counted in wildcard_plus and in
+// wildcard_hash.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TrieStats {
+    pub total: usize,
+    pub wildcard_plus: usize,
+    pub wildcard_hash: usize,
+    pub exact: usize,
 }
 
-impl Trie {
+// Trie stores a value of type T with every subscribed topic filter, e.g. the
+// QoS or the subscription identifier, Trie<()> only records the filters.
+pub struct Trie<T> {
+    root: RcTrieNode<T>,
+    interner: Interner,
+    separator: char,
+}
+
+impl<T> Trie<T> {
     pub fn new() -> Self {
         Self {
-            root: TrieNode::new(None, None, false),
+            root: TrieNode::new(None, None),
+            interner: Interner { levels: None },
+            separator: '/',
         }
     }
 
-    pub fn insert(&self, topic: &str) {
-        let mut current_node = self.root.clone();
-        let mut peekable = topic.split("/").peekable();
-        let parts = peekable.borrow_mut();
+    /// Creates a trie that splits the levels on a separator other than '/'.
+    /// This only changes how the trie keys the filters and topics passed to
+    /// it, e.g. "a.+.c" with '.', they must be translated from and to the
+    /// '/' used on the wire by the caller. The wildcards keep their meaning.
+    pub fn with_separator(separator: char) -> Self {
+        Self {
+            separator,
+            ..Self::new()
+        }
+    }
 
-        while let Some(part) = parts.next() {
+    /// Creates a trie that shares the level names between its nodes, which
+    /// saves memory when many filters use the same level names.
+    pub fn with_interning() -> Self {
+        Self {
+            root: TrieNode::new(None, None),
+            interner: Interner {
+                levels: Some(RefCell::new(HashSet::new())),
+            },
+            separator: '/',
+        }
+    }
+
+    /// Inserts a topic filter with its value, returns false when it was
+    /// already subscribed. The value of an existing subscription is replaced.
+    pub fn insert(&self, topic: &str, value: T) -> bool {
+        let mut current_node = self.root.clone();
+        for part in topic.split(self.separator) {
             let parent = current_node.clone();
-            let inserted = current_node.as_ref().borrow().get_or_insert_child(
-                part,
-                parent,
-                parts.peek().is_none(),
-            );
+            let inserted =
+                current_node
+                    .as_ref()
+                    .borrow()
+                    .get_or_insert_child(part, parent, &self.interner);
             current_node = inserted;
         }
+        // the node may already exist, e.g. for a retained message or as the
+        // parent of another filter
+        current_node.set_subscription(Some(value)).is_none()
+    }
+
+    /// Deletes a topic filter, returns false when it was not subscribed.
+    pub fn delete(&self, topic: &str) -> bool {
+        let node = match self.find_node(topic) {
+            Some(v) => v,
+            None => return false,
+        };
+        if !node.has_subscription() {
+            return false;
+        }
+        // the node stays while it has subscribed descendants or a retained
+        // message, only the subscription is cleared
+        node.set_subscription(None);
+        prune_node(node, &self.interner);
+        true
+    }
+
+    /// Removes every subscription while keeping the retained messages. The
+    /// branches that were only there for subscriptions are removed.
+    pub fn clear_subscriptions(&self) {
+        fn clear_node<T>(node: &RcTrieNode<T>, interner: &Interner) {
+            let children: Vec<RcTrieNode<T>> = node.children.borrow().values().cloned().collect();
+            for child in children {
+                child.set_subscription(None);
+                clear_node(&child, interner);
+                if !child.has_retained() && !child.has_children() {
+                    let key = child.value.clone().unwrap();
+                    node.remove_child(&key);
+                    drop(child);
+                    interner.release(key);
+                }
+            }
+        }
+        clear_node(&self.root, &self.interner);
+    }
+
+    fn find_node(&self, topic: &str) -> Option<RcTrieNode<T>> {
+        let mut current_node = self.root.clone();
+        for part in topic.split(self.separator) {
+            current_node = current_node.get_child(part)?;
+        }
+        Some(current_node)
     }
 
-    pub fn delete(&self, topic: &str) {
-        fn detach_child(node: &RcTrieNode) {
-            let borrowed_node = node.as_ref().borrow();
-            if borrowed_node.has_children() {
-                if node.has_subscription() {
-                    borrowed_node.set_subscription(false);
+    /// Returns the sorted names of the levels directly below a prefix, for
+    /// browsing the tree one level at a time. The prefix levels are taken
+    /// literally, wildcards included, and an empty prefix lists the first
+    /// level. Nodes that only hold a retained message are listed as well.
+    pub fn children_of(&self, prefix: &str) -> Vec<String> {
+        let node = if prefix.is_empty() {
+            Some(self.root.clone())
+        } else {
+            self.find_node(prefix)
+        };
+        let mut children: Vec<String> = match node {
+            Some(v) => v.children.borrow().keys().map(|k| k.to_string()).collect(),
+            None => Vec::new(),
+        };
+        children.sort();
+        children
+    }
+
+    /// Returns the most specific subscribed filter matching a topic name. The
+    /// levels are compared from the first one, a literal level is preferred
+    /// over '+' and '+' over '#'.
+    pub fn best_match(&self, topic: &str) -> Option<String> {
+        let (path, _) = self.find_best_match(topic)?;
+        let levels: Vec<&str> = path.iter().map(|v| v.as_ref()).collect();
+        Some(levels.join(&self.separator.to_string()))
+    }
+
+    // find_best_match returns the levels of the best matching filter and its
+    // node.
+    fn find_best_match(&self, topic: &str) -> Option<(Vec<Rc<str>>, RcTrieNode<T>)> {
+        fn find<T>(
+            node: &RcTrieNode<T>,
+            parts: &[&str],
+            path: &mut Vec<Rc<str>>,
+        ) -> Option<RcTrieNode<T>> {
+            if let Some((part, rest)) = parts.split_first() {
+                for key in [*part, "+"] {
+                    if let Some(child) = node.get_child(key) {
+                        path.push(child.value.clone().unwrap());
+                        if let Some(found) = find(&child, rest, path) {
+                            return Some(found);
+                        }
+                        path.pop();
+                    }
                 }
-                return;
+            } else if node.has_subscription() {
+                return Some(node.clone());
             }
 
-            let parent = borrowed_node.get_parent();
-            if parent.is_none() {
-                return;
+            // '#' matches the remaining levels, including none
+            match node.get_child("#") {
+                Some(child) if child.has_subscription() => {
+                    path.push(child.value.clone().unwrap());
+                    Some(child)
+                }
+                _ => None,
             }
+        }
+
+        let parts: Vec<&str> = topic.split(self.separator).collect();
+        let mut path = Vec::new();
+        let node = find(&self.root, &parts, &mut path)?;
+        Some((path, node))
+    }
 
-            if node.has_subscription() {
-                let parent_node = parent.as_ref().unwrap();
-                parent_node.remove_child(borrowed_node.value.as_ref().unwrap());
-                detach_child(parent_node);
+    /// Returns the subscribed topic filters, in no particular order.
+    pub fn subscriptions(&self) -> Vec<String> {
+        fn collect<T>(
+            node: &RcTrieNode<T>,
+            prefix: Option<&str>,
+            separator: char,
+            filters: &mut Vec<String>,
+        ) {
+            for (k, child) in node.children.borrow().iter() {
+                let filter = match prefix {
+                    Some(p) => format!("{}{}{}", p, separator, k),
+                    None => k.to_string(),
+                };
+                if child.has_subscription() {
+                    filters.push(filter.clone());
+                }
+                collect(child, Some(&filter), separator, filters);
             }
         }
+        let mut filters = Vec::new();
+        collect(&self.root, None, self.separator, &mut filters);
+        filters
+    }
 
-        let mut current_node = self.root.clone();
-        let parts = topic.split("/");
-        for part in parts {
-            let child = current_node.as_ref().borrow().get_child(part);
-            if child.is_none() {
-                return;
+    /// Reports whether a topic filter is subscribed, the levels are compared
+    /// as is, wildcards included.
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        match self.find_node(topic) {
+            Some(node) => node.has_subscription(),
+            None => false,
+        }
+    }
+
+    /// Returns a copy of the value of a subscribed topic filter, the levels
+    /// are compared as is, wildcards included.
+    pub fn get(&self, topic: &str) -> Option<T>
+    where
+        T: Clone,
+    {
+        let node = self.find_node(topic)?;
+        let subscription = node.subscription.borrow();
+        subscription.clone()
+    }
+
+    /// Returns a copy of the value of the most specific filter matching a
+    /// topic name, the filter is the one returned by best_match.
+    pub fn match_value(&self, topic: &str) -> Option<T>
+    where
+        T: Clone,
+    {
+        let (_, node) = self.find_best_match(topic)?;
+        let subscription = node.subscription.borrow();
+        subscription.clone()
+    }
+
+    /// Stores a retained message on the node of a topic name, creating the
+    /// node if needed. An empty payload removes the retained message. Returns
+    /// false for a topic containing wildcards, which is not a topic name and
+    /// must not create nodes used for matching.
+    pub fn set_retained(&self, topic: &str, payload: &[u8]) -> bool {
+        if topic.contains(['+', '#']) {
+            return false;
+        }
+        if payload.is_empty() {
+            if let Some(node) = self.find_node(topic) {
+                *node.retained.borrow_mut() = None;
+                prune_node(node, &self.interner);
             }
-            current_node = child.unwrap().clone();
+            return true;
         }
-        detach_child(&current_node);
+
+        let mut current_node = self.root.clone();
+        for part in topic.split(self.separator) {
+            let parent = current_node.clone();
+            current_node = current_node.get_or_insert_child(part, parent, &self.interner);
+        }
+        *current_node.retained.borrow_mut() = Some(payload.to_vec());
+        true
+    }
+
+    /// Returns a copy of the retained message stored for a topic name.
+    pub fn get_retained(&self, topic: &str) -> Option<Vec<u8>> {
+        let node = self.find_node(topic)?;
+        let retained = node.retained.borrow();
+        retained.clone()
     }
 
     pub fn contains(&self, topic: &str) -> bool {
-        return match_topic(&self.root, topic);
+        return match_topic(&self.root, topic, self.separator);
     }
 
     pub fn number_of_entries(&self) -> usize {
-        let stack = print_trie_nodes(&self.root);
-        return stack.len();
+        self.node_stats().1
+    }
+
+    /// Returns the number of nodes, the number of nodes holding a
+    /// subscription and the depth of the deepest node, counted in a single
+    /// traversal. The root is not counted, a node on the first level has a
+    /// depth of 1.
+    pub fn node_stats(&self) -> (usize, usize, usize) {
+        fn visit<T>(node: &RcTrieNode<T>, depth: usize, stats: &mut (usize, usize, usize)) {
+            for child in node.children.borrow().values() {
+                stats.0 += 1;
+                if child.has_subscription() {
+                    stats.1 += 1;
+                }
+                stats.2 = stats.2.max(depth + 1);
+                visit(child, depth + 1, stats);
+            }
+        }
+        let mut stats = (0, 0, 0);
+        visit(&self.root, 0, &mut stats);
+        stats
+    }
+
+    /// Returns the number of subscribed filters by kind, counted in a single
+    /// traversal.
+    pub fn stats(&self) -> TrieStats {
+        fn visit<T>(node: &RcTrieNode<T>, plus: bool, hash: bool, stats: &mut TrieStats) {
+            for (k, child) in node.children.borrow().iter() {
+                let plus = plus || k.as_ref() == "+";
+                let hash = hash || k.as_ref() == "#";
+                if child.has_subscription() {
+                    stats.total += 1;
+                    stats.wildcard_plus += plus as usize;
+                    stats.wildcard_hash += hash as usize;
+                    stats.exact += (!plus && !hash) as usize;
+                }
+                visit(child, plus, hash, stats);
+            }
+        }
+        let mut stats = TrieStats::default();
+        visit(&self.root, false, false, &mut stats);
+        stats
     }
 
     pub fn print_entries(&self) {
         let stack = print_trie_nodes(&self.root);
         for v in stack {
-            println!("{}", v.join("/"));
+            println!("{}", v.join(&self.separator.to_string()));
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Trie;
+    use std::rc::Rc;
+
+    use super::{Trie, TrieStats};
 
     #[test]
     fn test_basic() {
         let trie = Trie::new();
-        trie.insert("a/b/c/d");
-        trie.insert("a/b/c/d/x");
-        trie.insert("f/g/h");
-        trie.insert("i/j/k");
+        trie.insert("a/b/c/d", ());
+        trie.insert("a/b/c/d/x", ());
+        trie.insert("f/g/h", ());
+        trie.insert("i/j/k", ());
         assert_eq!(trie.number_of_entries(), 4);
-        trie.delete("a/b/c/d");
+        assert!(!trie.insert("f/g/h", ()));
+        assert!(trie.delete("a/b/c/d"));
+        assert_eq!(trie.number_of_entries(), 3);
+        assert!(!trie.delete("a/b/c/d"));
+        assert!(!trie.delete("f/g"));
+        assert!(trie.is_subscribed("f/g/h"));
+        assert!(!trie.is_subscribed("f/g"));
+        assert!(!trie.is_subscribed("f/+/h"));
+    }
+
+    #[test]
+    fn test_interning() {
+        let trie = Trie::with_interning();
+        let mut levels = 0;
+        for building in 0..10 {
+            for device in 0..10 {
+                for leaf in ["status", "temperature", "humidity"] {
+                    trie.insert(&format!("sensors/{}/{}/{}", building, device, leaf), ());
+                    levels += 4;
+                }
+            }
+        }
+        assert_eq!(trie.number_of_entries(), 300);
+        // sensors, 0-9 and the three leaf names
+        assert_eq!(trie.interner.len(), 14);
+        assert!(trie.interner.len() * 20 < levels);
+        assert!(trie.contains("sensors/3/7/humidity"));
+
+        let a = trie
+            .root
+            .get_child("sensors")
+            .unwrap()
+            .get_child("1")
+            .unwrap();
+        let b = trie
+            .root
+            .get_child("sensors")
+            .unwrap()
+            .get_child("2")
+            .unwrap();
+        let status_a = a.get_child("1").unwrap().get_child("status").unwrap();
+        let status_b = b.get_child("5").unwrap().get_child("status").unwrap();
+        assert!(Rc::ptr_eq(
+            status_a.value.as_ref().unwrap(),
+            status_b.value.as_ref().unwrap()
+        ));
+
+        drop((a, b, status_a, status_b));
+
+        // the names are released with the last node using them
+        for building in 0..10 {
+            for leaf in ["status", "temperature", "humidity"] {
+                assert!(trie.delete(&format!("sensors/{}/9/{}", building, leaf)));
+            }
+        }
+        assert_eq!(trie.interner.len(), 14);
+        for device in 0..9 {
+            for leaf in ["status", "temperature"] {
+                assert!(trie.delete(&format!("sensors/0/{}/{}", device, leaf)));
+            }
+        }
+        assert_eq!(trie.interner.len(), 14);
+        for building in 1..10 {
+            for device in 0..9 {
+                trie.delete(&format!("sensors/{}/{}/temperature", building, device));
+            }
+        }
+        // only temperature is gone, 9 is still a building
+        assert_eq!(trie.interner.len(), 13);
+        trie.set_retained("sensors/x/status", b"on");
+        assert_eq!(trie.interner.len(), 14);
+        trie.set_retained("sensors/x/status", b"");
+        assert_eq!(trie.interner.len(), 13);
+        trie.clear_subscriptions();
+        assert_eq!(trie.interner.len(), 0);
+        assert_eq!(trie.number_of_entries(), 0);
+
+        // the same trie without interning does not share the names
+        let trie = Trie::new();
+        trie.insert("a/status", ());
+        trie.insert("b/status", ());
+        assert_eq!(trie.interner.len(), 0);
+        let status_a = trie
+            .root
+            .get_child("a")
+            .unwrap()
+            .get_child("status")
+            .unwrap();
+        let status_b = trie
+            .root
+            .get_child("b")
+            .unwrap()
+            .get_child("status")
+            .unwrap();
+        assert!(!Rc::ptr_eq(
+            status_a.value.as_ref().unwrap(),
+            status_b.value.as_ref().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_clear_subscriptions() {
+        let trie = Trie::new();
+        trie.insert("a/b/c", ());
+        trie.insert("a/+", ());
+        trie.insert("x/y", ());
+        trie.insert("#", ());
+        trie.set_retained("a/b", b"retained");
+        trie.set_retained("a/b/c", b"leaf");
+        trie.set_retained("m/n/o", b"other");
+
+        trie.clear_subscriptions();
+        assert_eq!(trie.number_of_entries(), 0);
+        assert!(!trie.contains("a/b/c"));
+        assert!(!trie.contains("a/b"));
+        assert!(!trie.contains("x/y"));
+        assert_eq!(trie.get_retained("a/b"), Some(b"retained".to_vec()));
+        assert_eq!(trie.get_retained("a/b/c"), Some(b"leaf".to_vec()));
+        assert_eq!(trie.get_retained("m/n/o"), Some(b"other".to_vec()));
+
+        // only the branches holding retained messages are left
+        assert_eq!(trie.children_of(""), vec!["a", "m"]);
+        assert_eq!(trie.children_of("a"), vec!["b"]);
+
+        trie.insert("a/b", ());
+        assert!(trie.contains("a/b"));
+        assert_eq!(trie.number_of_entries(), 1);
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let trie = Trie::new();
+        let filters = ["a/b", "a", "a/+/c", "/a", "#", "a//"];
+        for filter in filters {
+            trie.insert(filter, ());
+        }
+        trie.set_retained("x/y", b"retained");
+
+        let mut subscriptions = trie.subscriptions();
+        subscriptions.sort();
+        let mut expected = filters.to_vec();
+        expected.sort();
+        assert_eq!(subscriptions, expected);
+    }
+
+    #[test]
+    fn test_best_match() {
+        let trie = Trie::new();
+        for filter in ["foo/#", "foo/+", "+/bar", "foo/bar", "#", "a/+/c", "a/b/#"] {
+            trie.insert(filter, ());
+        }
+        assert_eq!(trie.best_match("foo/bar").as_deref(), Some("foo/bar"));
+        assert_eq!(trie.best_match("foo/baz").as_deref(), Some("foo/+"));
+        assert_eq!(trie.best_match("foo/baz/x").as_deref(), Some("foo/#"));
+        assert_eq!(trie.best_match("foo").as_deref(), Some("foo/#"));
+        assert_eq!(trie.best_match("x/bar").as_deref(), Some("+/bar"));
+        assert_eq!(trie.best_match("x").as_deref(), Some("#"));
+        assert_eq!(trie.best_match("a/b/c").as_deref(), Some("a/b/#"));
+        assert_eq!(trie.best_match("a/x/c").as_deref(), Some("a/+/c"));
+
+        trie.delete("#");
+        assert_eq!(trie.best_match("x"), None);
+    }
+
+    #[test]
+    fn test_values() {
+        let trie: Trie<u8> = Trie::new();
+        assert!(trie.insert("a/b", 1));
+        assert!(trie.insert("a/+", 2));
+        assert!(trie.insert("#", 0));
+        assert_eq!(trie.get("a/b"), Some(1));
+        assert_eq!(trie.get("a/+"), Some(2));
+        assert_eq!(trie.get("a"), None);
+        assert_eq!(trie.get("a/c"), None);
+
+        // the QoS of the most specific matching filter
+        assert_eq!(trie.match_value("a/b"), Some(1));
+        assert_eq!(trie.match_value("a/c"), Some(2));
+        assert_eq!(trie.match_value("x/y"), Some(0));
+
+        // subscribing again replaces the value
+        assert!(!trie.insert("a/b", 2));
+        assert_eq!(trie.get("a/b"), Some(2));
         assert_eq!(trie.number_of_entries(), 3);
+
+        trie.delete("#");
+        assert_eq!(trie.match_value("x/y"), None);
+        trie.set_retained("x/y", b"retained");
+        assert_eq!(trie.get("x/y"), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let trie = Trie::new();
+        assert_eq!(trie.stats(), TrieStats::default());
+
+        for filter in ["a/b", "a/b/c", "a/+", "a/+/c", "+/#", "a/#", "#", "x"] {
+            trie.insert(filter, ());
+        }
+        trie.set_retained("m/n", b"retained");
+        assert_eq!(
+            trie.stats(),
+            TrieStats {
+                total: 8,
+                wildcard_plus: 3,
+                wildcard_hash: 3,
+                exact: 3,
+            }
+        );
+
+        trie.delete("+/#");
+        trie.delete("a/b");
+        let stats = trie.stats();
+        assert_eq!(stats.total, trie.number_of_entries());
+        assert_eq!(
+            (stats.wildcard_plus, stats.wildcard_hash, stats.exact),
+            (2, 2, 2)
+        );
+    }
+
+    #[test]
+    fn test_node_stats() {
+        let trie = Trie::new();
+        assert_eq!(trie.node_stats(), (0, 0, 0));
+
+        // a -> b -> c, a -> +, x -> y -> z -> w
+        trie.insert("a/b/c", ());
+        trie.insert("a/b", ());
+        trie.insert("a/+", ());
+        trie.set_retained("x/y/z/w", b"retained");
+        assert_eq!(trie.node_stats(), (8, 3, 4));
+        assert_eq!(trie.number_of_entries(), 3);
+
+        trie.delete("a/b/c");
+        assert_eq!(trie.node_stats(), (7, 2, 4));
+        trie.set_retained("x/y/z/w", b"");
+        assert_eq!(trie.node_stats(), (3, 2, 2));
+    }
+
+    #[test]
+    fn test_separator() {
+        let slash = Trie::new();
+        let dot = Trie::with_separator('.');
+        for filter in ["a/b/c", "a/+/c", "x/#", "/y", "m/n"] {
+            slash.insert(filter, ());
+            dot.insert(&filter.replace('/', "."), ());
+        }
+        dot.delete("m.n");
+        slash.delete("m/n");
+
+        for topic in ["a/b/c", "a/x/c", "a/b", "x", "x/y/z", "/y", "y", "m/n"] {
+            assert_eq!(
+                slash.contains(topic),
+                dot.contains(&topic.replace('/', ".")),
+                "{}",
+                topic
+            );
+            assert_eq!(
+                slash.best_match(topic).map(|v| v.replace('/', ".")),
+                dot.best_match(&topic.replace('/', ".")),
+                "{}",
+                topic
+            );
+        }
+        // '/' is an ordinary character with another separator
+        dot.insert("p/q", ());
+        assert!(dot.contains("p/q"));
+        assert!(!dot.contains("p.q"));
+        assert_eq!(dot.children_of(""), vec!["", "a", "p/q", "x"]);
+
+        let mut subscriptions = dot.subscriptions();
+        subscriptions.sort();
+        assert_eq!(subscriptions, vec![".y", "a.+.c", "a.b.c", "p/q", "x.#"]);
+    }
+
+    #[test]
+    fn test_children_of() {
+        let trie = Trie::new();
+        trie.insert("a/b", ());
+        trie.insert("a/c/d", ());
+        trie.insert("a/+/e", ());
+        trie.insert("x", ());
+        assert_eq!(trie.children_of("a"), vec!["+", "b", "c"]);
+        assert_eq!(trie.children_of("a/c"), vec!["d"]);
+        assert_eq!(trie.children_of("a/+"), vec!["e"]);
+        assert_eq!(trie.children_of(""), vec!["a", "x"]);
+        assert!(trie.children_of("a/b").is_empty());
+        assert!(trie.children_of("b").is_empty());
+        assert!(trie.children_of("a/c/d/e").is_empty());
+    }
+
+    #[test]
+    fn test_delete_intermediate_subscription() {
+        let trie = Trie::new();
+        trie.insert("a/b", ());
+        trie.insert("a/b/c", ());
+        assert!(trie.delete("a/b"));
+        assert!(!trie.contains("a/b"));
+        assert!(trie.contains("a/b/c"));
+        assert_eq!(trie.number_of_entries(), 1);
+
+        // deleting the child keeps the subscribed parent
+        trie.insert("a/b", ());
+        assert!(trie.delete("a/b/c"));
+        assert!(trie.contains("a/b"));
+        assert!(!trie.contains("a/b/c"));
+        assert_eq!(trie.number_of_entries(), 1);
+
+        // the unsubscribed intermediate nodes are pruned with the leaf
+        assert!(trie.delete("a/b"));
+        assert!(trie.root.get_child("a").is_none());
+
+        // an empty last level, "foo/" is a child of the subscribed "foo"
+        trie.insert("foo", ());
+        trie.insert("foo/", ());
+        assert!(trie.delete("foo/"));
+        assert!(trie.contains("foo"));
+        assert!(!trie.contains("foo/"));
+
+        // deleting a node that only exists as a parent
+        trie.insert("x/y/z", ());
+        assert!(!trie.delete("x/y"));
+        assert!(trie.contains("x/y/z"));
+    }
+
+    #[test]
+    fn test_match_wildcard_and_exact_branches() {
+        let trie = Trie::new();
+        trie.insert("a/+/c", ());
+        assert!(trie.contains("a/b/c"));
+        assert!(!trie.contains("a/b"));
+
+        // the '+' branch fails for "foo//bar", the exact branch must still
+        // see all the parts
+        trie.insert("foo/", ());
+        trie.insert("foo/+", ());
+        assert!(!trie.contains("foo//bar"));
+        assert!(trie.contains("foo/"));
+        assert!(trie.contains("foo/bar"));
+    }
+
+    #[test]
+    fn test_retained() {
+        let trie = Trie::new();
+        trie.insert("a/+/c", ());
+        trie.set_retained("a/b/c", b"hello");
+        trie.set_retained("a/b", b"world");
+        assert_eq!(trie.get_retained("a/b/c"), Some(b"hello".to_vec()));
+        assert_eq!(trie.get_retained("a/b"), Some(b"world".to_vec()));
+        assert_eq!(trie.get_retained("a"), None);
+        assert_eq!(trie.get_retained("a/+/c"), None);
+        // retained messages are not subscriptions
+        trie.set_retained("d/e", b"retained");
+        assert_eq!(trie.number_of_entries(), 1);
+        assert!(!trie.contains("d/e"));
+        trie.set_retained("d/e", b"");
+
+        trie.set_retained("a/b/c", b"updated");
+        assert_eq!(trie.get_retained("a/b/c"), Some(b"updated".to_vec()));
+
+        trie.set_retained("a/b/c", b"");
+        assert_eq!(trie.get_retained("a/b/c"), None);
+        assert_eq!(trie.get_retained("a/b"), Some(b"world".to_vec()));
+        trie.set_retained("a/b", b"");
+        assert_eq!(trie.get_retained("a/b"), None);
+        assert!(trie.root.get_child("a").unwrap().get_child("b").is_none());
+
+        // the subscription is untouched
+        assert_eq!(trie.number_of_entries(), 1);
+        assert!(trie.contains("a/x/c"));
+
+        // deleting a subscription keeps the retained message on the node
+        trie.insert("x/y", ());
+        trie.set_retained("x/y", b"kept");
+        trie.delete("x/y");
+        assert!(!trie.contains("x/y"));
+        assert_eq!(trie.get_retained("x/y"), Some(b"kept".to_vec()));
+
+        // subscribing to a topic that already has a retained message
+        assert!(trie.set_retained("m/n", b"retained"));
+        trie.insert("m/n", ());
+        assert!(trie.contains("m/n"));
+
+        // wildcards are rejected and do not create nodes used for matching
+        let trie: Trie<()> = Trie::new();
+        for topic in ["a/#", "a/+/c", "#", "+", "a/b+"] {
+            assert!(!trie.set_retained(topic, b"retained"));
+            assert!(!trie.set_retained(topic, b""));
+        }
+        assert!(!trie.root.has_children());
+        assert!(!trie.contains("a/b"));
     }
 }
@@ -0,0 +1,104 @@
+// Decodes every packet fixture in tests/interop, encodes the decoded packet
+// again and checks that the bytes are identical. A fixture is a hex dump of a
+// single packet, whitespace is ignored and lines starting with '#' are
+// comments.
+
+use std::{fs, io::Cursor, path::Path};
+
+use mqtt_rs::packet::{
+    connack::Connack,
+    connect::Connect,
+    packet::{FixedHeaderReader, PacketType},
+    publish::Publish,
+    suback::Suback,
+};
+use num::FromPrimitive;
+
+fn parse_fixture(content: &str) -> Vec<u8> {
+    let hex: String = content
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .flat_map(|l| l.split_whitespace())
+        .collect();
+    assert!(hex.len().is_multiple_of(2), "odd number of hex digits");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn round_trip(name: &str, data: &[u8]) {
+    let mut cur = Cursor::new(data);
+    let (byte0, remaining_len) = match FixedHeaderReader::read(&mut cur) {
+        Ok(v) => v,
+        Err(e) => panic!("{}: error reading the fixed header {}", name, e),
+    };
+    assert_eq!(
+        cur.position() as usize + remaining_len as usize,
+        data.len(),
+        "{}: remaining length does not match the fixture",
+        name
+    );
+
+    let encoded = match PacketType::from_u8(byte0 >> 4) {
+        Some(PacketType::CONNECT) => {
+            let connect = match Connect::read(&mut cur) {
+                Ok(v) => v,
+                Err(e) => panic!("{}: error reading CONNECT {}", name, e),
+            };
+            connect.write()
+        }
+        Some(PacketType::CONNACK) => {
+            let connack = match Connack::read(&mut cur) {
+                Ok(v) => v,
+                Err(e) => panic!("{}: error reading CONNACK {}", name, e),
+            };
+            connack.write()
+        }
+        Some(PacketType::PUBLISH) => {
+            let publish = match Publish::read(&mut cur, byte0 & 0x0F, remaining_len) {
+                Ok(v) => v,
+                Err(e) => panic!("{}: error reading PUBLISH {}", name, e),
+            };
+            publish.write()
+        }
+        Some(PacketType::SUBACK) => {
+            let suback = match Suback::read(&mut cur, remaining_len) {
+                Ok(v) => v,
+                Err(e) => panic!("{}: error reading SUBACK {}", name, e),
+            };
+            suback.write()
+        }
+        Some(t) => panic!("{}: no decoder for {} packets", name, t.as_str()),
+        None => unreachable!(),
+    };
+    assert_eq!(
+        cur.position() as usize,
+        data.len(),
+        "{}: the packet was not read completely",
+        name
+    );
+
+    match encoded {
+        Ok(v) => assert_eq!(v, data, "{}: re-encoded bytes differ", name),
+        Err(e) => panic!("{}: error writing the packet {}", name, e),
+    }
+}
+
+#[test]
+fn test_interop_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/interop");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "hex"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures found in {}", dir.display());
+
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let data = parse_fixture(&fs::read_to_string(&path).unwrap());
+        round_trip(&name, &data);
+    }
+}